@@ -0,0 +1,61 @@
+# loader of columnar simulation output (file frames.bin, written with option --columnar)
+
+import json
+import struct
+import zlib
+
+from pathlib import Path
+from typing import Dict
+
+import numpy as np
+
+
+MAGIC = b'VISAVISF'
+
+
+def load_frames(frames_path: Path) -> Dict[str, np.ndarray]:
+    """
+    Reads all frames of a run. Returns a dict of arrays, with frames sorted by time:
+      * 'time' (frames),
+      * 'alive' (frames × cells),
+      * 'molecules' (frames × cells × species, species as in the 'species' entry),
+      * 'ifne' (frames × cells × 2, lower and upper medium subcompartment),
+      * other cytokines by name, if given in parameters (likewise),
+      * 'virions' (frames × cells, free virions in the medium; only if simulated).
+    """
+    data = Path(frames_path).read_bytes()
+    assert data[:len(MAGIC)] == MAGIC
+    _version, header_len = struct.unpack_from('<II', data, len(MAGIC))
+    offset = len(MAGIC) + 8
+    header = json.loads(data[offset:offset + header_len])
+    offset += header_len
+
+    n_cells, n_species = header['n_cells'], len(header['molecules'])
+    names = header['cytokines']  # IFNeL, IFNeU, other cytokines (L, U), and possibly VIRe
+    times, alives, molecules, fields = [], [], [], []
+    while offset < len(data):
+        time, compressed_len = struct.unpack_from('<dQ', data, offset)
+        offset += 16
+        columns = zlib.decompress(data[offset:offset + compressed_len])
+        offset += compressed_len
+        u8s = np.frombuffer(columns, dtype=np.uint8, count=(1 + n_species) * n_cells)
+        f64s = np.frombuffer(columns, dtype='<f8', offset=(1 + n_species) * n_cells)
+        times.append(time)
+        alives.append(u8s[:n_cells].astype(bool))
+        molecules.append(u8s[n_cells:].reshape(n_species, n_cells).T)
+        fields.append(f64s.reshape(len(names), n_cells))
+
+    order = np.argsort(times)
+    frames = {
+        'time': np.array(times)[order],
+        'alive': np.stack(alives)[order],
+        'molecules': np.stack(molecules)[order],
+        'species': header['molecules'],
+    }
+    fields = np.stack(fields)[order]
+    for i in range(0, len(names) - 1, 2):
+        name = 'ifne' if names[i] == 'IFNeL' else names[i][:-1]
+        frames[name] = fields[:, i:i + 2].transpose(0, 2, 1)
+    if 'VIRe' in names:
+        frames['virions'] = fields[:, names.index('VIRe')]
+    return frames
@@ -5,6 +5,8 @@ from pathlib import Path
 import numpy as np
 import pandas as pd
 
+from .frames import load_frames
+
 
 class SimulationResult:
     """
@@ -20,6 +22,13 @@ class SimulationResult:
         # load all the csvs
         self._neighbors = pd.read_csv(self._simulation_dir / 'neighbors.csv')
 
+        if (self._simulation_dir / 'frames.bin').exists():
+            self._states = self._load_frames()
+        else:
+            self._states = self._load_csvs()
+        self._calculate_acts()
+
+    def _load_csvs(self):
         states_parts = []
         for states_part_path in self._simulation_dir.glob('t_*.csv'):
             (n, unit), = re.findall(r'(-?\d+)(h|m)\.csv', states_part_path.name)
@@ -30,8 +39,24 @@ class SimulationResult:
 
             states_parts.append(states_part)
 
-        self._states = pd.concat(states_parts)
-        self._calculate_acts()
+        return pd.concat(states_parts)
+
+    def _load_frames(self):
+        frames = load_frames(self._simulation_dir / 'frames.bin')
+        n_frames, n_cells = frames['alive'].shape
+        states = pd.DataFrame({
+            'id': np.tile(np.arange(n_cells), n_frames),
+            'alive': frames['alive'].reshape(-1).astype(int),
+            **{
+                species: frames['molecules'][:, :, i].reshape(-1)
+                for i, species in enumerate(frames['species'])
+            },
+            'IFNeL': frames['ifne'][:, :, 0].reshape(-1),
+            'IFNeU': frames['ifne'][:, :, 1].reshape(-1),
+            **({'VIRe': frames['virions'].reshape(-1)} if 'virions' in frames else {}),
+            'hour': np.repeat(np.round(frames['time']) / 60, n_cells),
+        })
+        return states
 
     def _calculate_acts(self):
         self._states['Vinf_act'] = self._states['Vinf'] >= 1
@@ -54,6 +79,10 @@ class SimulationResult:
     def states(self):
         return self._states
 
+    @property
+    def summary(self):
+        return pd.read_csv(self._simulation_dir / 'summary.csv')
+
     @property
     def hours(self):
         return sorted(set(self._states['hour']))
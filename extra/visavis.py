@@ -61,6 +61,7 @@ class VisAVisClient:
         verbose: bool = True,            # print information about progress
         images:   bool = False,          # save output images
         annotate: bool = False,          # annotate output images?
+        seed: Optional[int] = None,      # random generator seed (default: time-based)
     ) -> Optional[SimulationResult]:
 
         if isinstance(protocol_file_path, str):
@@ -93,6 +94,7 @@ class VisAVisClient:
             parameters.absolute(),
             protocol_file_dst_path.absolute(),
             *(['--images'] if images else []),
+            *(['--seed', str(seed)] if seed is not None else []),
         ], cwd=simulation_dir, stdout=subprocess.DEVNULL)
 
 
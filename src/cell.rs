@@ -4,11 +4,24 @@
 // Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
 
 use crate::molecule::Mol;
-use crate::molecule::N_MOLECULE_SPECIES;
+use crate::molecule::{MAX_MOLECULE_SPECIES, N_MOLECULE_SPECIES};
 
-type MolArray = [u8; N_MOLECULE_SPECIES];
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy)]
+type MolArray = [u8; MAX_MOLECULE_SPECIES];
+
+// levels of built-in molecules, followed by zeros in slots used only by models
+const fn levels(built_in: [u8; N_MOLECULE_SPECIES]) -> MolArray {
+    let mut levels = [0; MAX_MOLECULE_SPECIES];
+    let mut mi = 0;
+    while mi < N_MOLECULE_SPECIES {
+        levels[mi] = built_in[mi];
+        mi += 1;
+    }
+    levels
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct Cell {
     pub alive: bool,
     pub molecules: MolArray,
@@ -25,17 +38,17 @@ impl Cell {
 
     pub const MIN: Cell = Cell {
         alive: true,
-        molecules: [0, 0, 0, 0, 0, 0, 0],
+        molecules: levels([0, 0, 0, 0, 0, 0, 0]),
     };
 
     pub const MAX: Cell = Cell {
         alive: true,
-        molecules: [1, 3, 3, 3, 3, 3, 3],
+        molecules: levels([1, 3, 3, 3, 3, 3, 3]),
     };
 
     pub const ACT: Cell = Cell {
         alive: true,
-        molecules: [1, 3, 3, 3, 3, 1, 1],
+        molecules: levels([1, 3, 3, 3, 3, 1, 1]),
     };
 
     #[inline]
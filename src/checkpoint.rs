@@ -0,0 +1,181 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Checkpoints: complete simulation state saved to a JSON file, from which protocol execution
+// can be resumed so that it continues bit-identically. Rates in effect (possibly changed by
+// protocol commands) are saved, but the model is not, so it has to be given again when resuming.
+
+use crate::error::{Error, Result};
+use crate::lattice::Lattice;
+use crate::randomness::Generator;
+use crate::rates::Rates;
+use crate::simulation::RunProgress;
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub version: String,          // of the simulator that saved the checkpoint
+    pub command_i: usize,         // index of the protocol command to be executed (or continued)
+    pub out_init_frame: bool,     // whether the next run starts with an output frame
+    pub time: f64,                // (in minutes)
+    pub lattice: Lattice,
+    pub rng: Generator,
+    pub rates: Rates,
+    pub run: Option<RunProgress>, // set if saved in the middle of a run
+    #[serde(default)]
+    pub frames_len: Option<u64>, // byte length of the frames file, if written (see module frames)
+    #[serde(default)]
+    pub summary_len: Option<u64>, // likewise, of the summary file (see module summary)
+}
+
+impl Checkpoint {
+    // The file is replaced atomically, so that a run killed while saving leaves the previous
+    // checkpoint intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut out, self)?;
+        out.flush()?;
+        drop(out);
+        Ok(fs::rename(tmp_path, path)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut checkpoint: Checkpoint =
+            serde_json::from_reader(BufReader::new(File::open(path.as_ref())?))?;
+        if checkpoint.version != env!("CARGO_PKG_VERSION") {
+            return Err(Error::InvalidParameter(format!(
+                "checkpoint {} saved by version {}",
+                path.as_ref().display(),
+                checkpoint.version
+            )));
+        }
+        checkpoint.lattice.restore_neighborhoods()?;
+        Ok(checkpoint)
+    }
+}
+
+#[test]
+fn test_checkpoint_resume_is_bit_identical() {
+    use crate::lattice::Geometry;
+    use crate::output::Output;
+    use crate::protocol::Protocol;
+    use crate::randomness::initialize_generator;
+
+    let dir = std::env::temp_dir().join(format!("visavis-checkpoint-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let geometry = Geometry { width: 30, height: 30, occupancy: 1.0, ..Geometry::default() };
+    let csv_of = |lattice: &Lattice| {
+        let mut csv = Vec::<u8>::new();
+        lattice.write_csv(&rates, &mut csv).unwrap();
+        csv
+    };
+    let execute = |protocol_text: &str, seed, output: &Output| {
+        let protocol = Protocol::from_text(protocol_text).unwrap();
+        let mut rng = initialize_generator(seed);
+        let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+        protocol.execute(&mut lattice, &rates, &mut rng, output).unwrap();
+        csv_of(&lattice)
+    };
+
+    let protocol_text = "+RSV 0.1 MOI\nrun 0m...3h []\n=IFN 100 U/ml\nrun 3h...4h []";
+    let reference = execute(protocol_text, 0x5eed, &Output::default());
+
+    // periodic checkpoints (the last one in the middle of the second run) do not perturb the run
+    let output = Output { dir: dir.clone(), checkpoint_interval: Some(50.), ..Output::default() };
+    assert_eq!(execute(protocol_text, 0x5eed, &output), reference);
+    let checkpoint = Checkpoint::load(dir.join("checkpoint.json")).unwrap();
+    assert_eq!((checkpoint.command_i, checkpoint.run.is_some()), (3, true));
+    let protocol = Protocol::from_text(protocol_text).unwrap();
+    let resumed = protocol.resume(checkpoint, &rates, &Output::default()).unwrap();
+    assert_eq!(csv_of(&resumed), reference);
+
+    // a state saved between commands can be loaded into another protocol (and random generator)
+    let output = Output { dir: dir.clone(), ..Output::default() };
+    execute("+RSV 0.1 MOI\nrun 0m...3h []\nsave_state infected.json", 0x5eed, &output);
+    let load_text = format!("load_state {}\n=IFN 100 U/ml\nrun 3h...4h []", dir.join("infected.json").display());
+    assert_eq!(execute(&load_text, 0xfeed, &Output::default()), reference);
+
+    // (relative to the output directory, with the prefix, are both saved and loaded states)
+    let output = Output { dir: dir.clone(), prefix: "pre_".to_string(), ..Output::default() };
+    let text = "+RSV 0.1 MOI\nrun 0m...3h []\nsave_state infected.json\n+RSV 1 MOI\n\
+                load_state infected.json\n=IFN 100 U/ml\nrun 3h...4h []";
+    assert_eq!(execute(text, 0x5eed, &output), reference);
+    assert!(dir.join("pre_infected.json").is_file());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_resume_after_kill_continues_output_files() {
+    use crate::lattice::Geometry;
+    use crate::output::Output;
+    use crate::protocol::Protocol;
+    use crate::randomness::initialize_generator;
+    use std::path::PathBuf;
+
+    let dir = std::env::temp_dir().join(format!("visavis-killed-{}", std::process::id()));
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let geometry = Geometry { width: 20, height: 20, occupancy: 1.0, ..Geometry::default() };
+    let protocol = Protocol::from_text("+RSV 0.1 MOI\nrun 0m...4h [30m]").unwrap();
+    let output_in = |subdir: &str, checkpoint_interval| {
+        let dir = dir.join(subdir);
+        fs::create_dir_all(&dir).unwrap();
+        Output { dir, checkpoint_interval, quiet: true, ..Output::default() }
+    };
+    let execute = |mut output: Output| {
+        let mut rng = initialize_generator(0x5eed);
+        let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+        output.open_frames_file(&lattice, &rates, None).unwrap();
+        output.open_summary_file(&rates, None).unwrap();
+        protocol.execute(&mut lattice, &rates, &mut rng, &output).unwrap();
+    };
+    // (frames may be written in any order, so they are compared as sorted by time)
+    let frames_of = |dir: PathBuf| {
+        let bytes = fs::read(dir.join("frames.bin")).unwrap();
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let header_len = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        let (mut i, mut chunks) = (16 + header_len, Vec::new());
+        while i < bytes.len() {
+            let len = u64_at(i + 8) as usize;
+            chunks.push((u64_at(i), bytes[i + 16..i + 16 + len].to_vec()));
+            i += 16 + len;
+        }
+        chunks.sort();
+        (bytes[..16 + header_len].to_vec(), chunks)
+    };
+
+    execute(output_in("uninterrupted", None));
+
+    // the last checkpoint is in the middle of the run, as if the run was killed after it (while
+    // writing output, which is left incomplete)
+    execute(output_in("killed", Some(100.)));
+    let killed = dir.join("killed");
+    let append_to = |file_name, bytes: &[u8]| {
+        let mut file = fs::OpenOptions::new().append(true).open(killed.join(file_name)).unwrap();
+        file.write_all(bytes).unwrap();
+    };
+    append_to("frames.bin", &[0x5e; 13]);
+    append_to("summary.csv", b"240.000,4");
+    let checkpoint = Checkpoint::load(killed.join("checkpoint.json")).unwrap();
+    assert!(checkpoint.run.as_ref().is_some_and(|progress| (200. ..210.).contains(&progress.t)));
+    let mut output = output_in("killed", None);
+    output.open_frames_file(&checkpoint.lattice, &rates, checkpoint.frames_len).unwrap();
+    output.open_summary_file(&rates, checkpoint.summary_len).unwrap();
+    protocol.resume(checkpoint, &rates, &output).unwrap();
+
+    let uninterrupted = dir.join("uninterrupted");
+    let summary_of = |dir: &PathBuf| fs::read_to_string(dir.join("summary.csv")).unwrap();
+    assert_eq!(summary_of(&killed), summary_of(&uninterrupted));
+    assert_eq!(summary_of(&killed).lines().count(), 1 + 9);
+    assert_eq!(frames_of(killed), frames_of(uninterrupted));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -4,103 +4,296 @@
 // Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
 
 use crate::config::THREAD_STACK_SIZE;
+use crate::error::{Error, Result};
 use crate::lattice::Lattice;
-use crate::molecule::Mol;
+use crate::model::species_slot;
+use crate::molecule::{Mol, MOLECULE_NAMES};
+use crate::output::Output;
+use crate::randomness::Generator;
 use crate::rates::Rates;
-use crate::simulation::Simulation;
+use crate::region::Region;
+use crate::simulation::{Kinetics, RunProgress, Simulation};
 
-use rand::rngs::StdRng;
 use rand_distr::{Distribution, Poisson};
+use std::str::FromStr;
+use std::sync::mpsc;
 
-pub fn add_virus(lattice: &mut Lattice, rng: &mut StdRng, moi: f64) {
-    let poisson = Poisson::new(moi).unwrap();
+// subcompartments of the medium, see Lattice::cytokines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layer {
+    Lower,
+    Upper,
+    Both,
+}
+
+impl Layer {
+    fn indices(self) -> &'static [usize] {
+        match self {
+            Layer::Lower => &[0],
+            Layer::Upper => &[1],
+            Layer::Both => &[0, 1],
+        }
+    }
+}
+
+impl FromStr for Layer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lower" => Ok(Layer::Lower),
+            "upper" => Ok(Layer::Upper),
+            "both" => Ok(Layer::Both),
+            _ => Err(Error::InvalidParameter(format!("medium layer {:?}", s))),
+        }
+    }
+}
+
+// Slot of a molecule marking infection ("Vinf") or replication ("VRNA") of the virus, found by
+// name among species of the model, if given (see module model).
+fn virus_slot(rates: &Rates, m: Mol) -> Result<usize> {
+    species_slot(rates.model.as_deref(), m).ok_or_else(|| {
+        let name = MOLECULE_NAMES[m as usize];
+        Error::InvalidParameter(format!("model: no species {:?} of the virus", name))
+    })
+}
+
+pub fn add_virus(
+    lattice: &mut Lattice,
+    rates: &Rates,
+    rng: &mut Generator,
+    moi: f64,
+) -> Result<()> {
+    let poisson = Poisson::new(moi)
+        .map_err(|_| Error::InvalidParameter(format!("multiplicity of infection {}", moi)))?;
+    let vinf = virus_slot(rates, Mol::Vinf)?;
     lattice.cells.iter_mut().for_each(|c| {
         if c.alive {
             let vi = poisson.sample(rng) as u64;
             debug_assert!(vi < u8::max_value() as u64);
-            let has_virus = vi > 0 || c.molecules[Mol::Vinf as usize] > 0;
-            c.molecules[Mol::Vinf as usize] = if has_virus { 1 } else { 0 } as u8
+            let has_virus = vi > 0 || c.molecules[vinf] > 0;
+            c.molecules[vinf] = if has_virus { 1 } else { 0 } as u8
         }
     });
+    Ok(())
+}
+
+// Infects cells in the region, with the multiplicity of infection graded from the first to
+// the second value (see module region).
+pub fn add_virus_in(
+    lattice: &mut Lattice,
+    rates: &Rates,
+    rng: &mut Generator,
+    moi: (f64, f64),
+    region: &Region,
+) -> Result<()> {
+    if !(moi.0 >= 0. && moi.1 >= 0. && moi.0.is_finite() && moi.1.is_finite()) {
+        return Err(Error::InvalidParameter(format!("multiplicity of infection {:?}", moi)));
+    }
+    let vinf = virus_slot(rates, Mol::Vinf)?;
+    for (cell_i, grade) in region.grades(lattice)? {
+        let (c, moi) = (&mut lattice.cells[cell_i], moi.0 + grade * (moi.1 - moi.0));
+        if c.alive && moi > 0. {
+            let vi = Poisson::new(moi).unwrap().sample(rng) as u64;
+            if vi > 0 {
+                c.molecules[vinf] = 1
+            }
+        }
+    }
+    Ok(())
 }
 
+// (commands adding interferon leave nodes outside the domain without medium, see module mask)
 pub fn set_upper_ifne(lattice: &mut Lattice, upper_ifne_mlcs: f64) {
-    lattice.cytokines = [[0., upper_ifne_mlcs]; Lattice::CAPACITY]
+    for (c, &outside) in lattice.cytokines.iter_mut().zip(lattice.outside.iter()) {
+        *c = [0., if outside { 0. } else { upper_ifne_mlcs }]
+    }
+}
+
+// Sets interferon in the region, graded from the first to the second value (see module region).
+pub fn set_upper_ifne_in(
+    lattice: &mut Lattice,
+    upper_ifne_mlcs: (f64, f64),
+    region: &Region,
+) -> Result<()> {
+    let (start, end) = upper_ifne_mlcs;
+    if !(start >= 0. && end >= 0. && start.is_finite() && end.is_finite()) {
+        return Err(Error::InvalidParameter(format!("amount of IFNe {:?}", upper_ifne_mlcs)));
+    }
+    for (cell_i, grade) in region.grades(lattice)? {
+        if !lattice.outside[cell_i] {
+            lattice.cytokines[cell_i] = [0., start + grade * (end - start)]
+        }
+    }
+    Ok(())
+}
+
+pub fn add_upper_ifne(lattice: &mut Lattice, upper_ifne_mlcs: f64) {
+    for (c, &outside) in lattice.cytokines.iter_mut().zip(lattice.outside.iter()) {
+        if !outside {
+            c[1] += upper_ifne_mlcs
+        }
+    }
 }
 
 pub fn remove_ifne(lattice: &mut Lattice) {
-    lattice.cytokines = [[0., 0.]; Lattice::CAPACITY]
+    lattice.cytokines.iter_mut().for_each(|c| *c = [0., 0.])
+}
+
+pub fn remove_ifne_fraction(lattice: &mut Lattice, fraction: f64, layer: Layer) -> Result<()> {
+    if !(0. ..=1.).contains(&fraction) {
+        return Err(Error::InvalidParameter(format!("fraction of medium {}", fraction)));
+    }
+    for c in lattice.cytokines.iter_mut() {
+        layer.indices().iter().for_each(|&li| c[li] *= 1. - fraction)
+    }
+    Ok(())
+}
+
+pub fn dilute_ifne(lattice: &mut Lattice, factor: f64) -> Result<()> {
+    if !(factor >= 1. && factor.is_finite()) {
+        return Err(Error::InvalidParameter(format!("dilution factor {}", factor)));
+    }
+    lattice.cytokines.iter_mut().for_each(|c| *c = [c[0] / factor, c[1] / factor]);
+    Ok(())
+}
+
+// Washes out the inoculum: virus that has not yet started replication (no viral RNA) is removed,
+// as are free virions in the medium.
+pub fn remove_free_virus(lattice: &mut Lattice, rates: &Rates) -> Result<()> {
+    let (vinf, vrna) = (virus_slot(rates, Mol::Vinf)?, virus_slot(rates, Mol::Vrna)?);
+    lattice.cells.iter_mut().for_each(|c| {
+        if c.molecules[vrna] == 0 {
+            c.molecules[vinf] = 0
+        }
+    });
+    lattice.virions.iter_mut().for_each(|v| *v = 0.);
+    Ok(())
 }
 
 pub fn run_simulation_quietly(
     lattice: &mut Lattice,
     rates: &Rates,
-    rng: &mut StdRng,
+    rng: &mut Generator,
     tspan: (f64, f64),
-    images_out: bool,
+    output: &Output,
     init_frame_out: bool,
-) {
+) -> Result<()> {
     run_simulation_(
         lattice,
         rates,
+        &Kinetics::bind(rates, lattice)?,
         rng,
         tspan,
         /*files_out:*/ false,
-        images_out,
+        output,
         /*files_out_interval*/ -1.,
         init_frame_out,
+        /*resumed:*/ None,
+        /*t_stop:*/ f64::INFINITY,
     )
+    .map(|_| ())
 }
 
 pub fn run_simulation(
     lattice: &mut Lattice,
     rates: &Rates,
-    rng: &mut StdRng,
+    rng: &mut Generator,
     tspan: (f64, f64),
-    images_out: bool,
+    output: &Output,
     files_out_interval: f64,
     init_frame_out: bool,
-) {
+) -> Result<()> {
     run_simulation_(
         lattice,
         rates,
+        &Kinetics::bind(rates, lattice)?,
         rng,
         tspan,
         /*files_out:*/ true,
-        images_out,
+        output,
         files_out_interval,
         init_frame_out,
+        /*resumed:*/ None,
+        /*t_stop:*/ f64::INFINITY,
+    )
+    .map(|_| ())
+}
+
+// Runs a simulation (with output files if an interval is given), possibly resumed from the
+// progress of an interrupted run. If time t_stop is reached before the end of the run,
+// returns progress of the run.
+#[allow(clippy::too_many_arguments)]
+pub fn run_simulation_until(
+    lattice: &mut Lattice,
+    rates: &Rates,
+    kinetics: &Kinetics, // (bound to the rates)
+    rng: &mut Generator,
+    tspan: (f64, f64),
+    output: &Output,
+    files_out_interval: Option<f64>,
+    init_frame_out: bool,
+    resumed: Option<RunProgress>,
+    t_stop: f64,
+) -> Result<Option<RunProgress>> {
+    if resumed.as_ref().is_some_and(|progress| !progress.fits(lattice, rates)) {
+        return Err(Error::InvalidParameter("run progress does not fit the lattice or model".into()));
+    }
+    run_simulation_(
+        lattice,
+        rates,
+        kinetics,
+        rng,
+        tspan,
+        /*files_out:*/ files_out_interval.is_some(),
+        output,
+        files_out_interval.unwrap_or(-1.),
+        init_frame_out,
+        resumed,
+        t_stop,
     )
 }
 
 fn run_simulation_(
     lattice: &mut Lattice,
     rates: &Rates,
-    rng: &mut StdRng,
+    kinetics: &Kinetics,
+    rng: &mut Generator,
     tspan: (f64, f64),
     files_out: bool,
-    images_out: bool,
+    output: &Output,
     files_out_interval: f64,
     init_frame_out: bool,
-) {
-    let workers = Some(
+    resumed: Option<RunProgress>,
+    t_stop: f64,
+) -> Result<Option<RunProgress>> {
+    let (errors_tx, errors_rx) = mpsc::channel::<Error>();
+    let workers = Some((
         threadpool::Builder::new()
             .num_threads(num_cpus::get())
             .thread_stack_size(THREAD_STACK_SIZE)
             .build(),
-    );
-    Simulation::simulate(
+        errors_tx,
+    ));
+    let progress = Simulation::simulate(
         lattice,
         rates,
+        kinetics,
         rng,
         tspan,
         files_out,
-        images_out,
+        output,
         files_out_interval,
         /*ifni_secretion:*/ true,
         /*in_sep_thread:*/ false,
         init_frame_out,
+        resumed,
+        t_stop,
         &workers,
     );
-    workers.unwrap().join()
+    workers.unwrap().0.join();
+    match errors_rx.try_recv() {
+        Ok(err) => Err(err), // report the first output error
+        Err(_) => progress,
+    }
 }
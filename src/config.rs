@@ -4,7 +4,7 @@
 // Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
 
 // memory
-pub const THREAD_STACK_SIZE: usize = 8 * 1024 * 1024; // larger lattice would require larger stack!
+pub const THREAD_STACK_SIZE: usize = 8 * 1024 * 1024; // lattice data are on the heap
 
 // output files
 pub const OUT_FILE_NAME_TIME_IN_MIN: bool = true; // if false, then hours are used
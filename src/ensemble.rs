@@ -0,0 +1,130 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Ensemble-level time course of replicate runs, gathered from their summary files (see module
+// summary): one CSV row per sampling time, with the mean and quantiles (over replicates) of the
+// fraction of dead cells and of the fractions of cells in which each molecule is active. With
+// the built-in species, infected cells are those with active Vinf. Rows of replicates are
+// labeled with sampling times, in which the replicates have to agree.
+
+use crate::error::{Error, Result};
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+pub const QUANTILES: [f64; 5] = [0.05, 0.25, 0.5, 0.75, 0.95];
+
+// quantile of sorted values, linearly interpolated between the closest ranks
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let rank = q * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lo] + (rank - lo as f64) * (sorted[hi] - sorted[lo])
+}
+
+pub fn write_ensemble_summary<P, W>(summary_paths: &[P], out: W) -> Result<()>
+where
+    P: AsRef<Path>,
+    W: Write,
+{
+    let summaries = summary_paths
+        .iter()
+        .map(|path| fs::read_to_string(path.as_ref()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    summarize(&summaries, out)
+}
+
+// Summaries of replicates have to agree in species and in the number of sampling times.
+fn summarize<W: Write>(summaries: &[String], mut out: W) -> Result<()> {
+    let disagreement = |what: &str| {
+        Error::InvalidParameter(format!("summaries of replicates differ in {}", what))
+    };
+    let tables = summaries
+        .iter()
+        .map(|summary| summary.lines().map(|line| line.split(',').collect()).collect())
+        .collect::<Vec<Vec<Vec<&str>>>>();
+    let header = match tables.first().and_then(|table| table.first()) {
+        Some(header) => header,
+        _ => return Err(Error::InvalidParameter("no summaries of replicates".to_string())),
+    };
+    // (trailing columns of the medium, IFNe and possibly free virions, are not summarized)
+    let medium_column = match header.iter().position(|&name| name == "IFNeL_total") {
+        Some(column) if column > 3 => column,
+        _ => return Err(Error::InvalidParameter("summaries of replicates lack species".into())),
+    };
+    if tables.iter().any(|table| table.first() != Some(header)) {
+        return Err(disagreement("species"));
+    }
+    let n_rows = tables[0].len();
+    if tables.iter().any(|table| table.len() != n_rows) {
+        return Err(disagreement("the number of sampling times"));
+    }
+
+    // summarized are "dead" and the species (columns of counts after "alive")
+    let columns = 2..medium_column;
+    let mut out_header = vec!["time_min".to_string()];
+    for name in &header[columns.clone()] {
+        out_header.push(format!("{}_mean", name));
+        out_header.extend(QUANTILES.iter().map(|q| format!("{}_q{:02.0}", name, 100. * q)));
+    }
+    out.write_all([&out_header.join(","), "\n"].concat().as_bytes())?;
+    for row_i in 1..n_rows {
+        let time = tables[0][row_i][0];
+        time.parse::<f64>().map_err(|_| disagreement("format"))?;
+        let mut fractions = vec![Vec::with_capacity(tables.len()); columns.len()];
+        for table in &tables {
+            let row = &table[row_i];
+            if row.len() != header.len() {
+                return Err(disagreement("the number of columns"));
+            }
+            if row[0] != time {
+                return Err(disagreement("sampling times"));
+            }
+            let counts = row[1..medium_column]
+                .iter()
+                .map(|count| count.parse::<f64>().map_err(|_| disagreement("format")))
+                .collect::<Result<Vec<_>>>()?;
+            let n_nodes = counts[0] + counts[1]; // alive and dead
+            for (ci, count) in counts[1..].iter().enumerate() {
+                fractions[ci].push(count / n_nodes);
+            }
+        }
+        let mut line = vec![time.to_string()];
+        for values in fractions.iter_mut() {
+            values.sort_by(f64::total_cmp);
+            line.push(format!("{:.4}", values.iter().sum::<f64>() / values.len() as f64));
+            line.extend(QUANTILES.iter().map(|&q| format!("{:.4}", quantile(values, q))));
+        }
+        out.write_all([&line.join(","), "\n"].concat().as_bytes())?;
+    }
+    Ok(out.flush()?)
+}
+
+#[test]
+fn test_ensemble_summary_statistics() {
+    let header = "time_min,alive,dead,Vinf,ISG,IFNeL_total,IFNeL_mean,IFNeL_max,IFNeU_total,\
+                  IFNeU_mean,IFNeU_max";
+    let summaries = (0..5)
+        .map(|i| format!("{}\n0.000,10,0,{},0,0,0,0,0,0,0\n", header, i))
+        .collect::<Vec<_>>();
+    let mut out = Vec::<u8>::new();
+    summarize(&summaries, &mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    let lines = text.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0].split(',').take(8).collect::<Vec<_>>(),
+               ["time_min", "dead_mean", "dead_q05", "dead_q25", "dead_q50", "dead_q75", "dead_q95",
+                "Vinf_mean"]);
+    let vinf = lines[1].split(',').skip(7).take(6).collect::<Vec<_>>();
+    assert_eq!(vinf, ["0.2000", "0.0200", "0.1000", "0.2000", "0.3000", "0.3800"]);
+    assert!(lines[1].starts_with("0.000,"));
+
+    let mut shifted = summaries.clone();
+    shifted[2] = shifted[2].replace("\n0.000,", "\n0.100,");
+    assert!(summarize(&shifted, Vec::<u8>::new()).is_err());
+
+    let mut shortened = summaries.clone();
+    shortened[3] = shortened[3].lines().take(1).collect();
+    assert!(summarize(&shortened, Vec::<u8>::new()).is_err());
+}
@@ -0,0 +1,70 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+use crate::protocol::ProtocolError;
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Image(String),
+    Protocol(ProtocolError),
+    InvalidParameter(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Json(err) => write!(f, "JSON error: {}", err),
+            Error::Image(err) => write!(f, "image error: {}", err),
+            Error::Protocol(err) => write!(f, "protocol error: {}", err),
+            Error::InvalidParameter(err) => write!(f, "invalid parameter: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+impl From<cairo::Error> for Error {
+    fn from(err: cairo::Error) -> Self {
+        Error::Image(err.to_string())
+    }
+}
+
+impl From<cairo::IoError> for Error {
+    fn from(err: cairo::IoError) -> Self {
+        Error::Image(err.to_string())
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(err: png::DecodingError) -> Self {
+        Error::Image(err.to_string())
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Self {
+        Error::Protocol(err)
+    }
+}
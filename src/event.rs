@@ -26,6 +26,13 @@ pub enum Event {
     Die,
 }
 
+// names of rate constants of events (in Rates), in the order of event indices
+pub const RATE_NAMES: [&str; 15] = [
+    "vinf_incr", "vrna_incr", "vprot_incr", "pirf3_incr", "ifni_incr", "pstat_incr", "isg_incr",
+    "vinf_decr", "vrna_decr", "vprot_decr", "pirf3_decr", "ifni_decr", "pstat_decr", "isg_decr",
+    "die",
+];
+
 impl Event {
     pub fn rate_coef(self, rates: &Rates) -> f64 {
         match self {
@@ -68,7 +75,7 @@ impl Event {
         }
         macro_rules! current_cell_and_neighboring_cells {
             () => {
-                vec![cell_i, neighs[0], neighs[1], neighs[2], neighs[3], neighs[4], neighs[5]]
+                [&[cell_i][..], neighs].concat()
             };
         }
         let event = Event::from_index(event_i);
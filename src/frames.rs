@@ -0,0 +1,140 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Columnar output: all frames of a run are appended to a single binary file.
+//
+// The file starts with a magic string, a format version (u32), the byte length of a JSON
+// header (u32), and the JSON header itself, describing the lattice and the columns. Each frame
+// is stored as a chunk: time (f64), byte length of compressed data (u64), and zlib-compressed
+// columns, one after another: alive (u8 × cells), molecules (u8 × cells, for each species),
+// IFNe lower and upper (f64 × cells, each), other cytokines likewise, and free virions if
+// simulated (f64 × cells), as listed in the header as "cytokines". All numbers are
+// little-endian. Frames need not be ordered by time, as they are written concurrently.
+
+use crate::error::Result;
+use crate::lattice::Lattice;
+use crate::model::species_names;
+use crate::rates::Rates;
+
+use flate2::{write::ZlibEncoder, Compression};
+use std::io::Write;
+
+#[derive(Debug)]
+pub struct FramesWriter<W: Write> {
+    out: W,
+    n_cells: usize,
+    n_species: usize,
+    n_other_cytokines: usize,
+    free_virions: bool,
+}
+
+impl<W: Write> FramesWriter<W> {
+    pub const MAGIC: &'static [u8; 8] = b"VISAVISF";
+    pub const VERSION: u32 = 1;
+
+    pub fn new(mut out: W, lattice: &Lattice, rates: &Rates) -> Result<Self> {
+        let species_names = species_names(rates.model.as_deref());
+        let mut fields = vec!["IFNeL".to_string(), "IFNeU".to_string()];
+        for cytokine in rates.cytokines.iter() {
+            fields.extend([cytokine.name.clone() + "L", cytokine.name.clone() + "U"])
+        }
+        if rates.free_virions() {
+            fields.push("VIRe".to_string());
+        }
+        let header = serde_json::json!({
+            "width": lattice.width,
+            "height": lattice.height,
+            "n_cells": lattice.capacity(),
+            "alive": "u8",
+            "molecules": species_names,
+            "molecules_type": "u8",
+            "cytokines": fields,
+            "cytokines_type": "f64",
+        })
+        .to_string();
+        out.write_all(FramesWriter::<W>::MAGIC)?;
+        out.write_all(&FramesWriter::<W>::VERSION.to_le_bytes())?;
+        out.write_all(&(header.len() as u32).to_le_bytes())?;
+        out.write_all(header.as_bytes())?;
+        out.flush()?;
+        Ok(FramesWriter::appending(out, lattice, rates))
+    }
+
+    // for a file that already has the header (of the same lattice and species)
+    pub fn appending(out: W, lattice: &Lattice, rates: &Rates) -> Self {
+        let n_species = species_names(rates.model.as_deref()).len();
+        let (n_other_cytokines, free_virions) = (rates.cytokines.len(), rates.free_virions());
+        let n_cells = lattice.capacity();
+        FramesWriter { out, n_cells, n_species, n_other_cytokines, free_virions }
+    }
+
+    pub fn append(&mut self, time: f64, lattice: &Lattice) -> Result<()> {
+        debug_assert!(lattice.capacity() == self.n_cells);
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        let alive = lattice.cells.iter().map(|c| c.alive as u8).collect::<Vec<_>>();
+        zlib.write_all(&alive)?;
+        for mi in 0..self.n_species {
+            let mols = lattice.cells.iter().map(|c| c.molecules[mi]).collect::<Vec<_>>();
+            zlib.write_all(&mols)?;
+        }
+        for j in 0..=1 {
+            for cytokines in lattice.cytokines.iter() {
+                zlib.write_all(&cytokines[j].to_le_bytes())?;
+            }
+        }
+        for ci in 0..self.n_other_cytokines {
+            for j in 0..=1 {
+                for cell_i in 0..self.n_cells {
+                    zlib.write_all(&lattice.other_cytokine(ci, cell_i)[j].to_le_bytes())?;
+                }
+            }
+        }
+        if self.free_virions {
+            for virions in lattice.virions.iter() {
+                zlib.write_all(&virions.to_le_bytes())?;
+            }
+        }
+        let compressed = zlib.finish()?;
+
+        // the whole chunk is assembled first to be written in one go
+        let mut chunk = Vec::with_capacity(16 + compressed.len());
+        chunk.extend_from_slice(&time.to_le_bytes());
+        chunk.extend_from_slice(&(compressed.len() as u64).to_le_bytes());
+        chunk.extend_from_slice(&compressed);
+        self.out.write_all(&chunk)?;
+        Ok(self.out.flush()?)
+    }
+}
+
+#[test]
+fn test_frames_chunk_layout() {
+    use crate::lattice::Geometry;
+    use crate::molecule::N_MOLECULE_SPECIES;
+    use flate2::read::ZlibDecoder;
+    use rand::SeedableRng;
+    use std::io::Read;
+
+    let mut rng = SeedableRng::from_seed([123; 32]);
+    let geometry = Geometry { width: 4, height: 3, occupancy: 0.5, ..Geometry::default() };
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    lattice.cytokines[5] = [1.5, 2.5];
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let mut frames = FramesWriter::new(Vec::<u8>::new(), &lattice, &rates).unwrap();
+    frames.append(60., &lattice).unwrap();
+
+    let bytes = frames.out;
+    let header_len = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+    let chunk = &bytes[16 + header_len..];
+    assert_eq!(f64::from_le_bytes(chunk[0..8].try_into().unwrap()), 60.);
+    let compressed_len = u64::from_le_bytes(chunk[8..16].try_into().unwrap()) as usize;
+    assert_eq!(chunk.len(), 16 + compressed_len);
+    let mut columns = Vec::new();
+    ZlibDecoder::new(&chunk[16..]).read_to_end(&mut columns).unwrap();
+    let n = lattice.capacity();
+    assert_eq!(columns.len(), n * (1 + N_MOLECULE_SPECIES) + 2 * 8 * n);
+    assert_eq!(columns[..n].iter().filter(|&&a| a == 1).count(), 6);
+    let ifne_hi_5 = n * (1 + N_MOLECULE_SPECIES) + 8 * (n + 5);
+    assert_eq!(f64::from_le_bytes(columns[ifne_hi_5..ifne_hi_5 + 8].try_into().unwrap()), 2.5);
+}
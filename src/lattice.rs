@@ -5,35 +5,193 @@
 
 use crate::cell::Cell;
 use crate::config::OUT_FILE_NAME_TIME_IN_MIN;
-use crate::molecule::{Mol, Mol::{Vinf, Vrna, Vprot, Pirf3, Pstat}, N_MOLECULE_SPECIES};
+use crate::error::{Error, Result};
+use crate::mask::{self, NodeKind};
+use crate::model::{max_level, species_names, species_slot};
+use crate::molecule::Mol::{Ifni, Isg, Pirf3, Pstat, Vinf, Vprot, Vrna};
+use crate::output::Output;
+use crate::randomness::Generator;
 use crate::rates::Rates;
+use crate::topology::{self, voronoi_raster, Contacts, Topology};
+use crate::variability::{self, RateFactors, Variability};
 use crate::units::{MIN, HOUR};
 
 use cairo::{Context, Format, ImageSurface};
-use rand::{rngs::StdRng, seq::SliceRandom};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+use std::collections::BTreeMap;
 use std::f64::consts::PI;
-use std::fs::{File, OpenOptions};
-use std::io::{prelude::*, LineWriter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{prelude::*, BufReader, LineWriter};
+use std::ops::Index;
+use std::path::Path;
+use std::str::FromStr;
 
-type CellArray = [Cell; Lattice::CAPACITY];
-pub type CytokineArray = [[f64; 2]; Lattice::CAPACITY]; // IFNe: lo,hi
-type Neighborhoods = [[usize; Lattice::N_NEIGHBORS]; Lattice::CAPACITY];
+type CellArray = Vec<Cell>;
+pub type CytokineArray = Vec<[f64; 2]>; // IFNe: lo,hi
 
-#[derive(Clone)]
+// boundary conditions at the edges of the lattice
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Boundary {
+    #[default]
+    Periodic, // opposite edges are joined (the lattice is a torus)
+    Reflecting, // no flux through the edges
+    Absorbing,  // the medium beyond the edges is a sink of cytokines and free virions
+}
+
+impl FromStr for Boundary {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "periodic" => Ok(Boundary::Periodic),
+            "reflecting" => Ok(Boundary::Reflecting),
+            "absorbing" => Ok(Boundary::Absorbing),
+            _ => Err(Error::InvalidParameter(format!("boundary {:?}", s))),
+        }
+    }
+}
+
+// Neighbors of each lattice node, with weights of their contacts (see module topology); unless
+// the boundary is periodic, nodes at the edges have fewer neighbors.
+#[derive(Clone, Default)]
+pub struct Neighborhoods {
+    neighbors: Vec<Vec<usize>>,
+    weights: Vec<Vec<f64>>,
+    exchange_weights: Vec<f64>,
+}
+
+impl Neighborhoods {
+    // (with absorbing boundary, contacts with the surroundings of the domain are kept)
+    fn new(contacts: Vec<Contacts>, boundary: Boundary) -> Self {
+        let total = |c: &Contacts| {
+            let absorbed = if boundary == Boundary::Absorbing { c.beyond } else { 0. };
+            c.neighbors.iter().fold(0., |sum, &(_, weight)| sum + weight) + absorbed
+        };
+        let neighbors = |c: &Contacts| c.neighbors.iter().map(|&(j, _)| j).collect();
+        let weights = |c: &Contacts| c.neighbors.iter().map(|&(_, weight)| weight).collect();
+        Neighborhoods {
+            neighbors: contacts.iter().map(neighbors).collect(),
+            weights: contacts.iter().map(weights).collect(),
+            exchange_weights: contacts.iter().map(total).collect(),
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Vec<usize>> {
+        self.neighbors.iter()
+    }
+
+    // neighbors of the node with weights of their contacts
+    #[inline]
+    pub fn weighted(&self, i: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        self.neighbors[i].iter().copied().zip(self.weights[i].iter().copied())
+    }
+
+    // Total weight of contacts of the node in exchange of medium contents, which at absorbing
+    // boundaries include the surroundings of the domain.
+    #[inline]
+    pub fn exchange_weight(&self, i: usize) -> f64 {
+        self.exchange_weights[i]
+    }
+
+    // (bounds the outflow from any node, see Rates::check_transport_stability)
+    pub fn max_exchange_weight(&self) -> f64 {
+        self.exchange_weights.iter().copied().fold(0., f64::max)
+    }
+}
+
+impl Index<usize> for Neighborhoods {
+    type Output = [usize];
+
+    #[inline]
+    fn index(&self, i: usize) -> &[usize] {
+        &self.neighbors[i]
+    }
+}
+
+// lattice dimensions (given in the parameters file, all entries optional)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Geometry {
+    pub width: usize,
+    pub height: usize,
+    pub occupancy: f64, // used as ceil(width * height * the given fraction)
+    pub boundary: Boundary,
+    pub mask: Option<String>, // of the domain (see module mask)
+    pub topology: Topology,
+    // (of rate constants among cells, given here as their factors belong to lattice nodes, see
+    // module variability)
+    pub variability: BTreeMap<String, Variability>,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Geometry {
+            width: 100,
+            height: 100, // (non-square lattice shapes are also supported)
+            occupancy: 1.0,
+            boundary: Boundary::Periodic,
+            mask: None,
+            topology: Topology::Hexagonal,
+            variability: BTreeMap::new(),
+        }
+    }
+}
+
+impl Geometry {
+    // (paths of mask images are given relative to the parameters file)
+    pub fn from_json_file<P: AsRef<Path>>(params_filename: P) -> Result<Self> {
+        let contents = fs::read_to_string(params_filename.as_ref())?;
+        let mut geometry: Geometry = from_str(&contents)?;
+        if let Some(mask) = geometry.mask.as_mut().filter(|mask| !mask::is_shape(mask)) {
+            let base_dir = params_filename.as_ref().parent().unwrap_or(Path::new(""));
+            *mask = base_dir.join(&mask).display().to_string();
+        }
+        Ok(geometry)
+    }
+
+    // kinds of lattice nodes, as given by the mask (all occupied if there is no mask), for nodes
+    // at given positions in the plane (see Lattice::position)
+    pub fn node_kinds(&self, positions: &[(f64, f64)]) -> Result<Vec<NodeKind>> {
+        match &self.mask {
+            Some(mask) => mask::node_kinds(mask, self.width, self.height, positions),
+            None => Ok(vec![NodeKind::Occupied; self.capacity()]),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.width * self.height
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Lattice {
-    pub neighborhoods: Neighborhoods,
+    pub width: usize,
+    pub height: usize,
+    #[serde(default)]
+    pub boundary: Boundary,
+    #[serde(default)]
+    pub outside: Vec<bool>, // nodes outside the domain, given by the mask (see module mask)
+    #[serde(default)]
+    pub topology: Topology,
+    #[serde(default)]
+    pub seeds: Vec<[f64; 2]>, // of Voronoi cells (only in the Voronoi topology)
+    #[serde(skip)]
+    pub neighborhoods: Neighborhoods, // (regenerated when deserialized, see module checkpoint)
     pub cells: CellArray,
     pub cytokines: CytokineArray,
+    pub other_cytokines: Vec<CytokineArray>, // (of Rates::cytokines, see Lattice::fit_cytokines)
+    pub virions: Vec<f64>, // free virions in the medium (see Rates::free_virions)
+    #[serde(default)]
+    pub rate_factors: Vec<RateFactors>, // of cells (see module variability)
 }
 
 impl Lattice {
-    pub const N_NEIGHBORS: usize = 6; // fixed "kissing number" of the lattice, do not change
-
-    pub const WIDTH: usize = 100; // if you increase this, increase also config::THREAD_STACK_SIZE!
-    pub const HEIGHT: usize = Lattice::WIDTH; // (non-square lattice shapes are also supported)
-    pub const CAPACITY: usize = Lattice::WIDTH * Lattice::HEIGHT;
-
-    pub const OCCUPANCY: f64 = 1.0; // used as ceil(WIDTH * HEIGHT * the given fraction)
+    // "kissing number" of the hexagonal lattice (with periodic boundary), and the mean total
+    // weight of contacts of a node in the Voronoi topology (see module topology)
+    pub const N_NEIGHBORS: usize = 6;
 
     // lattice output
     pub const NEIGHS_TO_FILE: bool = true; // whether lattice neighbor indices are to be dumped
@@ -41,107 +199,252 @@ impl Lattice {
     pub const IMAGE_RECTANGULAR: bool = true; // if true, the parallelogram-shaped lattice is
                                               // right-to-left wrapped to form a rectangle
 
-    pub fn new(rng: &mut StdRng) -> Self {
-        Lattice {
-            neighborhoods: Lattice::generate_neighborhods(),
-            cells: Lattice::populate_cells(rng),
-            cytokines: [[0., 0.]; Lattice::CAPACITY],
+    pub fn new(geometry: &Geometry, rng: &mut Generator) -> Result<Self> {
+        if geometry.width == 0 || geometry.height == 0 {
+            return Err(Error::InvalidParameter("lattice width and height must be positive".into()));
         }
+        if !(0. ..=1.).contains(&geometry.occupancy) {
+            return Err(Error::InvalidParameter("lattice occupancy must be in [0, 1]".into()));
+        }
+        let seeds = match geometry.topology {
+            Topology::Voronoi => topology::voronoi_seeds(geometry.width, geometry.height, rng),
+            _ => Vec::new(),
+        };
+        let mut lattice = Lattice {
+            width: geometry.width,
+            height: geometry.height,
+            boundary: geometry.boundary,
+            outside: vec![false; geometry.capacity()],
+            topology: geometry.topology,
+            seeds,
+            neighborhoods: Neighborhoods::default(),
+            cells: Vec::new(),
+            cytokines: vec![[0., 0.]; geometry.capacity()],
+            other_cytokines: Vec::new(),
+            virions: vec![0.; geometry.capacity()],
+            rate_factors: Vec::new(),
+        };
+        let positions = (0..geometry.capacity()).map(|i| lattice.position(i)).collect::<Vec<_>>();
+        let node_kinds = geometry.node_kinds(&positions)?;
+        lattice.outside = node_kinds.iter().map(|&kind| kind == NodeKind::Outside).collect();
+        lattice.neighborhoods = lattice.generate_neighborhods();
+        lattice.cells = Lattice::populate_cells(geometry, &node_kinds, rng);
+        lattice.rate_factors =
+            variability::sample_rate_factors(&geometry.variability, geometry.capacity(), rng)?;
+        Ok(lattice)
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.cells.len()
     }
 
-    fn generate_neighborhods() -> Neighborhoods {
-        let mut nbhoods = [[usize::max_value(); Lattice::N_NEIGHBORS]; Lattice::CAPACITY];
-        fn as_index(x: usize, y: usize) -> usize {
-            x + y * Lattice::WIDTH
+    // Completes a lattice deserialized without neighborhoods (see module checkpoint).
+    pub fn restore_neighborhoods(&mut self) -> Result<()> {
+        let capacity = self.width * self.height;
+        if self.outside.is_empty() {
+            self.outside = vec![false; capacity]; // (not saved before masks were introduced)
         }
-        for (i, nbs) in nbhoods.iter_mut().enumerate() {
-            let (x, y) = ((i % Lattice::WIDTH) as usize, (i / Lattice::WIDTH) as usize);
-            let (east, west) = (
-                (x + 1) % Lattice::WIDTH,
-                (x + Lattice::WIDTH - 1) % Lattice::WIDTH,
-            );
-            let (south, north) = (
-                (y + 1) % Lattice::HEIGHT,
-                (y + Lattice::HEIGHT - 1) % Lattice::HEIGHT,
-            );
-            *nbs = [
-                as_index(east, y),
-                as_index(west, y),
-                as_index(x, south),
-                as_index(x, north),
-                as_index(west, south),
-                as_index(east, north),
-            ];
+        let mut sizes = vec![self.cells.len(), self.cytokines.len(), self.virions.len()];
+        sizes.push(self.outside.len());
+        if self.topology == Topology::Voronoi {
+            sizes.push(self.seeds.len())
         }
-        if Lattice::NEIGHS_TO_FILE {
-            let nbsf = File::create("neighbors.csv").expect("☠ ☆ neighs");
-            let mut nbsf = LineWriter::new(nbsf);
-            nbsf.write_all(b"left,right\n").expect("☠ ✏ neighs");
-            for (i, nbs) in nbhoods.iter_mut().enumerate() {
-                for nbi in nbs.iter() {
-                    if nbi > &i {
-                        nbsf.write_fmt(format_args!("{:},{:}\n", i, nbi))
-                            .expect("☠ ✏ neighs");
-                    }
+        sizes.extend(self.other_cytokines.iter().map(Vec::len));
+        sizes.extend(self.rate_factors.iter().map(|rate_factors| rate_factors.factors.len()));
+        if capacity == 0 || sizes.iter().any(|&size| size != capacity) {
+            return Err(Error::InvalidParameter("lattice size does not match its contents".into()));
+        }
+        self.neighborhoods = self.generate_neighborhods();
+        Ok(())
+    }
+
+    // Provides (zero) amounts of cytokines other than IFNe, if more are given in rates than
+    // present in the lattice.
+    pub fn fit_cytokines(&mut self, rates: &Rates) {
+        if self.other_cytokines.len() < rates.cytokines.len() {
+            let capacity = self.capacity();
+            self.other_cytokines.resize(rates.cytokines.len(), vec![[0., 0.]; capacity])
+        }
+    }
+
+    // Slots in rate_factors of the given rate constants (by name, if any), so that their factors
+    // are not looked up by name in runs (see Kinetics::bind).
+    pub fn rate_factor_slots<'a>(
+        &self,
+        names: impl Iterator<Item = Option<&'a str>>,
+    ) -> Vec<Option<usize>> {
+        let slot = |name: &str| self.rate_factors.iter().position(|factors| factors.name == name);
+        names.map(|name| name.and_then(slot)).collect()
+    }
+
+    // amounts of the cytokine (other than IFNe) in the lower and upper subcompartment
+    #[inline]
+    pub fn other_cytokine(&self, ci: usize, cell_i: usize) -> [f64; 2] {
+        self.other_cytokines.get(ci).map_or([0., 0.], |cytokines| cytokines[cell_i])
+    }
+
+    // coordinates of the node: (x, y) of node x + y * width, or the seed of its Voronoi cell
+    #[inline]
+    pub fn coordinates(&self, i: usize) -> (f64, f64) {
+        match self.topology {
+            Topology::Voronoi => (self.seeds[i][0], self.seeds[i][1]),
+            _ => ((i % self.width) as f64, (i / self.width) as f64),
+        }
+    }
+
+    // (in the plane, see Topology::position)
+    pub fn position(&self, i: usize) -> (f64, f64) {
+        self.topology.position(self.coordinates(i))
+    }
+
+    // Neighbors of nodes are given by the topology (see module topology), in the hexagonal
+    // lattice, in order, E, W, S, N, SW, NE. With the periodic boundary, they wrap around the
+    // edges, otherwise neighbors beyond the edges are omitted, as are nodes outside the domain
+    // (which have no neighbors).
+    fn generate_neighborhods(&self) -> Neighborhoods {
+        let (width, height) = (self.width, self.height);
+        let periodic = self.boundary == Boundary::Periodic;
+        let contacts = match self.topology {
+            Topology::Voronoi => {
+                topology::voronoi_contacts(&self.seeds, width, height, periodic, &self.outside)
+            }
+            grid => topology::grid_contacts(grid, width, height, periodic, &self.outside),
+        };
+        Neighborhoods::new(contacts, self.boundary)
+    }
+
+    pub fn save_neighbors(&self, output: &Output) -> Result<()> {
+        let nbsf = File::create(output.file_path("neighbors.csv"))?;
+        let mut nbsf = LineWriter::new(nbsf);
+        nbsf.write_all(b"left,right,weight\n")?;
+        for i in 0..self.capacity() {
+            for (nbi, weight) in self.neighborhoods.weighted(i) {
+                if nbi > i {
+                    nbsf.write_fmt(format_args!("{:},{:},{:}\n", i, nbi, weight))?;
                 }
             }
         }
-        nbhoods
+        Ok(())
     }
 
-    fn populate_cells(rng: &mut StdRng) -> CellArray {
-        let mut cells = [Cell {
+    // (the occupancy is the fraction of nodes occupied among those marked as such by the mask)
+    fn populate_cells(
+        geometry: &Geometry,
+        node_kinds: &[NodeKind],
+        rng: &mut Generator,
+    ) -> CellArray {
+        let mut cells = vec![Cell {
             alive: true,
-            molecules: [0; N_MOLECULE_SPECIES],
-        }; Lattice::CAPACITY];
-        let n_free_nodes = ((1.0 - Lattice::OCCUPANCY) * (cells.len() as f64)) as usize;
-        (0..cells.len())
-            .collect::<Vec<_>>()
-            .choose_multiple(rng, n_free_nodes)
-            .for_each(|i| cells[*i].alive = false);
+            molecules: Cell::MIN.molecules,
+        }; geometry.capacity()];
+        let occupied = (0..cells.len()).filter(|&i| node_kinds[i] == NodeKind::Occupied);
+        let occupied = occupied.collect::<Vec<_>>();
+        let n_free_nodes = ((1.0 - geometry.occupancy) * (occupied.len() as f64)) as usize;
+        occupied.choose_multiple(rng, n_free_nodes).for_each(|i| cells[*i].alive = false);
+        for (cell, &kind) in cells.iter_mut().zip(node_kinds) {
+            cell.alive &= kind == NodeKind::Occupied
+        }
         cells
     }
 
-    fn save_png(&self, time: f64, rates: &Rates) {
+    // number of lattice nodes within the domain (see module mask)
+    pub fn n_inside(&self) -> usize {
+        self.outside.iter().filter(|&&outside| !outside).count()
+    }
+
+    fn save_png(&self, time: f64, rates: &Rates, output: &Output) -> Result<()> {
         const IMG_SCALING: f64 = 20. * ((Lattice::IMAGE_RESOLUTION as f64) / 100.);
         const R: f64 = IMG_SCALING;
         const H: f64 = IMG_SCALING * 1.732_050 / 2.;
         const X0: f64 = 2. * H;
         const Y0: f64 = 1.5 * R;
-        const HEIGHT: f64 = (1.5 * (Lattice::HEIGHT as f64) + 1.5) * R;
-        const WIDTH: f64 = (2. * (Lattice::WIDTH as f64)
-            + 1.
-            + (if Lattice::IMAGE_RECTANGULAR { 2 } else { Lattice::HEIGHT }) as f64)
-            * H;
-
-        let sf = ImageSurface::create(Format::Rgb24, WIDTH as i32, HEIGHT as i32).unwrap();
-        let cx = Context::new(&sf).unwrap();
+        const U: f64 = 2. * H; // (spacing of neighbors, in other topologies)
+        let (width, height) = match self.topology {
+            Topology::Hexagonal => (
+                (2. * (self.width as f64)
+                    + 1.
+                    + (if Lattice::IMAGE_RECTANGULAR { 2 } else { self.height }) as f64)
+                    * H,
+                (1.5 * (self.height as f64) + 1.5) * R,
+            ),
+            _ => ((self.width as f64 + 1.) * U, (self.height as f64 + 1.) * U),
+        };
+
+        // raster points of Voronoi cells (see topology::voronoi_raster)
+        const RASTER: usize = topology::VORONOI_RASTER;
+        let (raster_width, raster) = match self.topology {
+            Topology::Voronoi => {
+                let periodic = self.boundary == Boundary::Periodic;
+                let raster = voronoi_raster(&self.seeds, self.width, self.height, periodic);
+                (RASTER * self.width, raster)
+            }
+            _ => (0, Vec::new()),
+        };
+        let mut raster_points = vec![Vec::new(); self.capacity()];
+        for (point_i, &cell_i) in raster.iter().enumerate() {
+            raster_points[cell_i].push(point_i)
+        }
+        let raster_square = |point_i: usize| {
+            let (a, b) = ((point_i % raster_width) as f64, (point_i / raster_width) as f64);
+            let side = U / RASTER as f64;
+            (U / 2. + a * side, U / 2. + b * side, side)
+        };
+
+        let sf = ImageSurface::create(Format::Rgb24, width as i32, height as i32)?;
+        let cx = Context::new(&sf)?;
         cx.set_source_rgb(0., 0., 0.);
         cx.paint().unwrap_or_else(|err| println!("☠ ✏ lattice: {:?}", err));
         cx.set_line_width(0.02 * IMG_SCALING);
 
-        for cell_i in 0..Lattice::CAPACITY {
+        // slots of molecules (found by name among species of the model, if given)
+        let model = rates.model.as_deref();
+        let slots = [Vinf, Vrna, Vprot, Pirf3, Ifni, Pstat, Isg].map(|m| species_slot(model, m));
+
+        for cell_i in (0..self.capacity()).filter(|&i| !self.outside[i]) {
             // cell index --> its (x, y) coordinates
-            let (mut i, j) = (cell_i % Lattice::WIDTH, cell_i / Lattice::WIDTH);
-            if Lattice::IMAGE_RECTANGULAR {
-                i = (i + j / 2) % Lattice::WIDTH
-            }
-            let (x, y) = (
-                X0 + (2. * (i as f64) + (if Lattice::IMAGE_RECTANGULAR {j % 2} else {j} as f64)) * H,
-                Y0 + 1.5 * (j as f64) * R,
-            );
+            let (x, y) = match self.topology {
+                Topology::Hexagonal => {
+                    let (mut i, j) = (cell_i % self.width, cell_i / self.width);
+                    if Lattice::IMAGE_RECTANGULAR {
+                        i = (i + j / 2) % self.width
+                    }
+                    let j_shift = if Lattice::IMAGE_RECTANGULAR { j % 2 } else { j };
+                    (X0 + (2. * (i as f64) + j_shift as f64) * H, Y0 + 1.5 * (j as f64) * R)
+                }
+                _ => {
+                    let (x, y) = self.coordinates(cell_i);
+                    (U * (x + 1.), U * (y + 1.))
+                }
+            };
 
-            // -- hexagon
+            // -- hexagon (or square, or Voronoi cell)
 
             // contour
-            cx.move_to(x, y + R * 0.99);
-            for a in 2..=6 {
-                let z = f64::from(a) * PI / 3.;
-                cx.rel_line_to(R * 0.99 * z.sin(), R * 0.99 * z.cos())
+            match self.topology {
+                Topology::Hexagonal => {
+                    cx.move_to(x, y + R * 0.99);
+                    for a in 2..=6 {
+                        let z = f64::from(a) * PI / 3.;
+                        cx.rel_line_to(R * 0.99 * z.sin(), R * 0.99 * z.cos())
+                    }
+                    cx.close_path();
+                }
+                Topology::Square4 | Topology::Square8 => {
+                    cx.rectangle(x - U * 0.495, y - U * 0.495, U * 0.99, U * 0.99)
+                }
+                Topology::Voronoi => {
+                    for &point_i in raster_points[cell_i].iter() {
+                        let (x, y, side) = raster_square(point_i);
+                        cx.rectangle(x, y, side, side)
+                    }
+                }
             }
-            cx.close_path();
             cx.set_source_rgb(0.1, 0.1, 0.1);
-            cx.stroke_preserve().unwrap_or_else(|err| println!("☠ ✏ lattice: {:?}", err));
+            if self.topology != Topology::Voronoi {
+                // (contours of Voronoi cells are drawn below, separately)
+                cx.stroke_preserve().unwrap_or_else(|err| println!("☠ ✏ lattice: {:?}", err));
+            }
 
             // fill (according to IFNe in lower subcompartment)
             let ifne_lo = self.cytokines[cell_i][0];
@@ -154,24 +457,30 @@ impl Lattice {
                 continue;
             }
 
+            // (in Voronoi cells, of unit area on average, rings are smaller to avoid overlaps)
+            let (ring_r, circle_r) = match self.topology {
+                Topology::Voronoi => (0.5 * R, 0.28 * R),
+                _ => (0.72 * R, 0.40 * R),
+            };
+
             // -- hexagon interior: ring and circle
 
-            // a molecule count
+            // a molecule count (zero if the model lacks the molecule)
             macro_rules! mlf {
                 ($m:ident) => {
-                    self.cells[cell_i].molecules[$m as usize] as f64
+                    slots[$m as usize].map_or(0., |mi| self.cells[cell_i].molecules[mi] as f64)
                 };
             }
             // max count of a molecule
             macro_rules! mxf {
                 ($m:ident) => {
-                    Cell::MAX.molecules[$m as usize] as f64
+                    slots[$m as usize].map_or(0., |mi| max_level(model, mi) as f64)
                 };
             }
             // fraction of molecules
             macro_rules! fxn {
                 ($m:ident) => {
-                    mlf!($m) / mxf!($m)
+                    if mxf!($m) > 0. { mlf!($m) / mxf!($m) } else { 0. }
                 };
             }
 
@@ -179,30 +488,50 @@ impl Lattice {
             let infxn = ((if mlf!(Vinf) > 0. { 1. } else { 0. }) + mlf!(Vrna) + mlf!(Vprot))
                 / (1. + mxf!(Vrna) + mxf!(Vprot));
             cx.set_source_rgb(0.15 + 0.85*infxn, 0.15, 0.15 + 0.85*infxn);
-            cx.arc(x, y, 0.72 * R, 0., 2. * PI);
+            cx.arc(x, y, ring_r, 0., 2. * PI);
             cx.fill_preserve().unwrap_or_else(|err| println!("☠ ✏ lattice: {:?}", err));
             cx.set_source_rgb(0., 0., 0.);
             cx.stroke().unwrap_or_else(|err| println!("☠ ✏ lattice: {:?}", err));
 
             // inner circle
             cx.set_source_rgb(0.15 + 0.85*fxn!(Pirf3), 0.15 + 0.85*fxn!(Pstat), 0.15);
-            cx.arc(x, y, 0.40 * R, 0., 2. * PI);
+            cx.arc(x, y, circle_r, 0., 2. * PI);
             cx.fill_preserve().unwrap_or_else(|err| println!("☠ ✏ lattice: {:?}", err));
             cx.set_source_rgb(0.15, 0.15, 0.15);
             cx.stroke().unwrap_or_else(|err| println!("☠ ✏ lattice: {:?}", err));
         } // for each cell (lattice node)
 
+        // contours of Voronoi cells, between raster points of different cells
+        if !raster.is_empty() {
+            let raster_height = raster.len() / raster_width;
+            for (point_i, &cell_i) in raster.iter().enumerate() {
+                let (a, b) = (point_i % raster_width, point_i / raster_width);
+                let (x, y, side) = raster_square(point_i);
+                if a + 1 < raster_width && raster[point_i + 1] != cell_i {
+                    cx.move_to(x + side, y);
+                    cx.rel_line_to(0., side);
+                }
+                if b + 1 < raster_height && raster[point_i + raster_width] != cell_i {
+                    cx.move_to(x, y + side);
+                    cx.rel_line_to(side, 0.);
+                }
+            }
+            cx.set_source_rgb(0.1, 0.1, 0.1);
+            cx.stroke().unwrap_or_else(|err| println!("☠ ✏ lattice: {:?}", err));
+        }
+
         // write out image to a PNG file
         let png_fn = if OUT_FILE_NAME_TIME_IN_MIN {
             ["t_", &format!("{:0>4.0}", time / MIN), "m.png"].concat()
         } else {
             ["t_", &format!("{:0>4.0}", time / HOUR), "h.png"].concat()
         };
-        let mut png = File::create(png_fn).expect("☠ ☆ PNG.");
-        sf.write_to_png(&mut png).expect("☠ ✏ PNG.");
+        let mut png = File::create(output.file_path(&png_fn))?;
+        sf.write_to_png(&mut png)?;
+        Ok(())
     }
 
-    fn save_csv(&self, time: f64) {
+    fn save_csv(&self, time: f64, rates: &Rates, output: &Output) -> Result<()> {
         // create and open CSV file for writing
         let csv_fn = if OUT_FILE_NAME_TIME_IN_MIN {
             ["t_", &format!("{:0>4.0}", time / MIN), "m.csv"].concat()
@@ -213,62 +542,274 @@ impl Lattice {
             .create(true)
             .truncate(true)
             .write(true)
-            .open(csv_fn)
-            .expect("☠ ☆ CSV");
+            .open(output.file_path(&csv_fn))?;
+        self.write_csv(rates, &mut csv)
+    }
 
+    // Molecule columns correspond to species of the model, if given (see module model). IFNe is
+    // followed by other cytokines, if given (see Rates::cytokines), and free virions, if simulated,
+    // and then by factors of rate constants varying among cells (see module variability).
+    pub fn write_csv<W: Write>(&self, rates: &Rates, csv: &mut W) -> Result<()> {
         // write out header
-        let hdr = "id,alive,Vinf,VRNA,Vprot,pIRF3,IFNi,pSTAT,ISG,IFNeL,IFNeU\n";
-        csv.write_all(hdr.as_bytes()).expect("☠ ✏ CSV");
+        let hdr = [&self.csv_header(rates), "\n"].concat();
+        csv.write_all(hdr.as_bytes())?;
 
-        // write out the state of each cell and the amount of IFNe above the cell
-        for cell_i in 0..Lattice::CAPACITY {
+        // write out the state of each cell and the amount of IFNe (and virions) above the cell
+        let n_species = species_names(rates.model.as_deref()).len();
+        for cell_i in 0..self.capacity() {
             let mut line: Vec<String> = vec![
                 cell_i.to_string(),
                 (if self.cells[cell_i].alive { "1" } else { "0" }).to_string(),
             ];
-            macro_rules! count_s {
-                ($m:ident) => {
-                    self.cells[cell_i].molecules[$m as usize].to_string()
-                };
-            }
-            for m in vec![
-                Mol::Vinf,
-                Mol::Vrna,
-                Mol::Vprot,
-                Mol::Pirf3,
-                Mol::Ifni,
-                Mol::Pstat,
-                Mol::Isg,
-            ] {
-                line.push(count_s!(m))
+            for mi in 0..n_species {
+                line.push(self.cells[cell_i].molecules[mi].to_string())
             }
             for j in 0..=1 {
                 line.push(format!("{:.3e}", self.cytokines[cell_i][j]))
             }
+            for ci in 0..rates.cytokines.len() {
+                let amounts = self.other_cytokine(ci, cell_i);
+                line.extend(amounts.iter().map(|amount| format!("{:.3e}", amount)))
+            }
+            if rates.free_virions() {
+                line.push(format!("{:.3e}", self.virions[cell_i]))
+            }
+            for rate_factors in self.rate_factors.iter() {
+                line.push(rate_factors.factors[cell_i].to_string())
+            }
             let mut line_s = line.join(",");
             line_s.push('\n');
-            csv.write_all(line_s.as_bytes()).expect("☠ ✏ CSV");
+            csv.write_all(line_s.as_bytes())?;
         } // for each cell/lattice node
+        Ok(())
+    }
+
+    fn csv_header(&self, rates: &Rates) -> String {
+        let species_names = species_names(rates.model.as_deref());
+        let mut hdr = ["id,alive,", &species_names.join(","), ",IFNeL,IFNeU"].concat();
+        for cytokine in rates.cytokines.iter() {
+            hdr.push_str(&format!(",{0}L,{0}U", cytokine.name))
+        }
+        if rates.free_virions() {
+            hdr.push_str(",VIRe")
+        }
+        for rate_factors in self.rate_factors.iter() {
+            hdr.push_str(&format!(",{}_factor", rate_factors.name))
+        }
+        hdr
+    }
+
+    // Loads a lattice state saved in a CSV file (see read_csv).
+    pub fn load_csv<P: AsRef<Path>>(&mut self, rates: &Rates, csv_path: P) -> Result<()> {
+        let csv = BufReader::new(File::open(csv_path.as_ref())?);
+        self.read_csv(rates, csv).map_err(|err| match err {
+            Error::InvalidParameter(message) => {
+                Error::InvalidParameter(format!("{}, {}", csv_path.as_ref().display(), message))
+            }
+            err => err,
+        })
+    }
+
+    // Reads back the state of all cells and cytokines (and virions, and factors of rate constants
+    // varying among cells), in the format of write_csv, into the lattice (of the same size). Rows
+    // may come in any order, but each lattice node has to be given exactly once, with levels
+    // within bounds (Cell::MAX, or given by the model), and with no molecules in dead cells. The
+    // lattice is left intact if the input is invalid.
+    pub fn read_csv<R: BufRead>(&mut self, rates: &Rates, csv: R) -> Result<()> {
+        let model = rates.model.as_deref();
+        let species_names = species_names(model);
+        let invalid = |line_i: usize, message: String| {
+            Error::InvalidParameter(format!("line {}: {}", line_i + 1, message))
+        };
+
+        let mut lines = csv.lines().enumerate();
+        let hdr = self.csv_header(rates);
+        match lines.next() {
+            Some((_, line)) if line.as_ref().is_ok_and(|line| line.trim_end() == hdr) => (),
+            Some((_, Err(err))) => return Err(err.into()),
+            _ => return Err(invalid(0, format!("header other than {:?}", hdr))),
+        }
+
+        self.fit_cytokines(rates);
+        let (mut cells, mut cytokines) = (self.cells.clone(), self.cytokines.clone());
+        let mut other_cytokines = self.other_cytokines.clone();
+        let (mut virions, mut rate_factors) = (self.virions.clone(), self.rate_factors.clone());
+        let n_amounts = 2 + 2 * rates.cytokines.len() + rates.free_virions() as usize;
+        let n_fields = 2 + species_names.len() + n_amounts + rate_factors.len();
+        let mut given = vec![false; self.capacity()];
+        for (line_i, line) in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let error = |message: String| Err(invalid(line_i, message));
+            let fields = line.trim_end().split(',').collect::<Vec<_>>();
+            let n_species = species_names.len();
+            if fields.len() != n_fields {
+                return error(format!("{} fields instead of {}", fields.len(), n_fields));
+            }
+            let cell_i = match fields[0].parse::<usize>() {
+                Ok(cell_i) if cell_i < self.capacity() && !given[cell_i] => cell_i,
+                Ok(cell_i) if cell_i < self.capacity() => {
+                    return error(format!("repeated id {}", cell_i))
+                }
+                _ => return error(format!("id {:?} out of lattice", fields[0])),
+            };
+            given[cell_i] = true;
+            let cell = &mut cells[cell_i];
+            cell.alive = match fields[1] {
+                "1" => true,
+                "0" => false,
+                alive => return error(format!("alive {:?} other than 0 or 1", alive)),
+            };
+            for (mi, field) in fields[2..2 + n_species].iter().enumerate() {
+                cell.molecules[mi] = match field.parse::<u8>() {
+                    Ok(level) if level <= max_level(model, mi) => level,
+                    _ => {
+                        let name = species_names[mi];
+                        return error(format!("{} level {:?} out of bounds", name, field));
+                    }
+                };
+            }
+            if !cell.alive && cell.molecules.iter().any(|&level| level > 0) {
+                return error("molecules in a dead cell".to_string());
+            }
+            if cell.alive && self.outside[cell_i] {
+                return error("cell outside the domain".to_string());
+            }
+            let mut amounts = Vec::with_capacity(n_amounts);
+            for field in fields[2 + n_species..2 + n_species + n_amounts].iter() {
+                match field.parse::<f64>() {
+                    Ok(amount) if amount.is_finite() && amount >= 0. => amounts.push(amount),
+                    _ => return error(format!("amount {:?} not a non-negative number", field)),
+                }
+            }
+            cytokines[cell_i] = [amounts[0], amounts[1]];
+            for ci in 0..rates.cytokines.len() {
+                other_cytokines[ci][cell_i] = [amounts[2 + 2 * ci], amounts[3 + 2 * ci]];
+            }
+            if rates.free_virions() {
+                virions[cell_i] = amounts[n_amounts - 1];
+            }
+            let factor_fields = fields[2 + n_species + n_amounts..].iter();
+            for (rate_factors, field) in rate_factors.iter_mut().zip(factor_fields) {
+                rate_factors.factors[cell_i] = match field.parse::<f64>() {
+                    Ok(factor) if factor.is_finite() && factor >= 0. => factor,
+                    _ => return error(format!("factor {:?} not a non-negative number", field)),
+                };
+            }
+        }
+        let n_given = given.iter().filter(|&&g| g).count();
+        if n_given != self.capacity() {
+            return Err(Error::InvalidParameter(format!(
+                "{} rows given for a lattice of {} nodes",
+                n_given,
+                self.capacity()
+            )));
+        }
+        (self.cells, self.cytokines) = (cells, cytokines);
+        (self.other_cytokines, self.virions) = (other_cytokines, virions);
+        self.rate_factors = rate_factors;
+        Ok(())
     }
 
     // save output file(s)
-    pub fn out(&self, time: f64, rates: &Rates, dump_image: bool) {
-        if dump_image {
-            self.save_png(time, rates);
+    pub fn out(&self, time: f64, rates: &Rates, output: &Output) -> Result<()> {
+        if output.images {
+            self.save_png(time, rates, output)?;
+        }
+        match &output.frames {
+            Some(frames) => frames.lock().unwrap().append(time, self),
+            None => self.save_csv(time, rates, output),
         }
-        self.save_csv(time);
     }
 }
 
 #[test]
 fn test_lattice_neighborhood_reflectivity() {
     use rand::SeedableRng;
-    let mut rng: StdRng = SeedableRng::from_seed([123; 32]);
-    let nbhoods = &Lattice::new(&mut rng).neighborhoods;
-    for i in 0..nbhoods.len() {
+    let mut rng: Generator = SeedableRng::from_seed([123; 32]);
+    let nbhoods = &Lattice::new(&Geometry::default(), &mut rng).unwrap().neighborhoods;
+    for i in 0..Geometry::default().capacity() {
         assert_eq!(nbhoods[i].len(), Lattice::N_NEIGHBORS);
         assert_eq!(nbhoods[ nbhoods[i][0/*E */] ][1/*W */], i);
         assert_eq!(nbhoods[ nbhoods[i][2/*S */] ][3/*N */], i);
         assert_eq!(nbhoods[ nbhoods[i][4/*SW*/] ][5/*NE*/], i);
     }
 }
+
+#[test]
+fn test_lattice_csv_roundtrip() {
+    use crate::commands::add_virus;
+    use crate::randomness::initialize_generator;
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let geometry = Geometry { width: 6, height: 4, occupancy: 0.75, ..Geometry::default() };
+    let mut rng = initialize_generator(0x5eed);
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    add_virus(&mut lattice, &rates, &mut rng, 1.).unwrap();
+    lattice.cytokines[3] = [1.5e3, 2.5e-2];
+    let mut csv = Vec::new();
+    lattice.write_csv(&rates, &mut csv).unwrap();
+
+    let mut loaded = Lattice::new(&Geometry { occupancy: 1., ..geometry }, &mut rng).unwrap();
+    loaded.read_csv(&rates, csv.as_slice()).unwrap();
+    let mut reloaded_csv = Vec::new();
+    loaded.write_csv(&rates, &mut reloaded_csv).unwrap();
+    assert_eq!(reloaded_csv, csv);
+
+    let text = String::from_utf8(csv).unwrap();
+    let rows = text.lines().collect::<Vec<_>>();
+    let row_0_id_24 = rows[1].replacen('0', "24", 1);
+    let defective = [
+        [&rows[..2], &rows[3..]].concat(),         // missing row
+        [&rows[..], &rows[1..2]].concat(),         // repeated id
+        [&rows[..], &[row_0_id_24.as_str()]].concat(), // id out of lattice
+        [&rows[..1], &["0,1,2,0,0,0,0,0,0,0,0"], &rows[2..]].concat(), // Vinf above Cell::MAX
+    ];
+    for lines in defective {
+        let before = loaded.cells.clone();
+        assert!(loaded.read_csv(&rates, lines.join("\n").as_bytes()).is_err());
+        assert!(loaded.cells.iter().zip(before.iter()).all(|(a, b)| a.molecules == b.molecules));
+    }
+}
+
+#[test]
+fn test_lattice_boundaries() {
+    use rand::SeedableRng;
+    let mut rng: Generator = SeedableRng::from_seed([123; 32]);
+    for boundary in [Boundary::Reflecting, Boundary::Absorbing] {
+        let geometry = Geometry { width: 5, height: 4, boundary, ..Geometry::default() };
+        let nbhoods = Lattice::new(&geometry, &mut rng).unwrap().neighborhoods;
+        assert_eq!(nbhoods[0], [1, 5]); // (corner: E, S)
+        assert_eq!(nbhoods[4], [3, 9, 8]); // (corner: W, S, SW)
+        assert_eq!(nbhoods[7].len(), Lattice::N_NEIGHBORS);
+        for (i, nbs) in nbhoods.iter().enumerate() {
+            assert!(nbs.iter().all(|&j| nbhoods[j].contains(&i)));
+        }
+        let exchange_weight = if boundary == Boundary::Absorbing { 6. } else { 2. };
+        assert_eq!(nbhoods.exchange_weight(0), exchange_weight);
+    }
+}
+
+#[test]
+fn test_lattice_topologies() {
+    use rand::SeedableRng;
+    let mut rng: Generator = SeedableRng::from_seed([123; 32]);
+    for topology in [Topology::Square4, Topology::Square8, Topology::Voronoi] {
+        let geometry = Geometry { width: 8, height: 6, topology, ..Geometry::default() };
+        let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+        let nbhoods = lattice.neighborhoods.clone();
+        for i in 0..lattice.capacity() {
+            for (j, weight) in nbhoods.weighted(i) {
+                assert!(nbhoods.weighted(j).any(|(k, w)| k == i && w == weight));
+            }
+            assert!(nbhoods.exchange_weight(i) <= nbhoods.max_exchange_weight());
+        }
+        let serialized = serde_json::to_string(&lattice).unwrap();
+        lattice = serde_json::from_str(&serialized).unwrap();
+        lattice.restore_neighborhoods().unwrap();
+        for i in 0..lattice.capacity() {
+            assert_eq!(lattice.neighborhoods.exchange_weight(i), nbhoods.exchange_weight(i));
+        }
+    }
+}
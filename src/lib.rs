@@ -0,0 +1,57 @@
+// -------------------------------------------------------------------------------------------------
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// This code features the research article:
+//
+//       "Antagonism between viral infection and innate immunity at the single-cell level"
+//
+//                               by Frederic Grabowski et al.
+//                                [TODO:JOURNAL-NAME], 202X
+//
+// The simulation mimicks the innate immune response to an infection with an RNA virus.
+// The hard-coded and externally parametrized interactions between host cell and virus
+// are specific to the respiratory syncytial virus (RSV). Infected cells attempt to produce
+// and secrete interferon, which alerts the non-infected bystander cells about the nearby
+// threat. The simulator executes alternating phases of (deterministic) interferon diffusion
+// and (stochastic) chemical kinetics.
+//
+// For more info, see file ReadMe.md.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+// -------------------------------------------------------------------------------------------------
+
+pub mod cell;
+pub mod checkpoint;
+pub mod commands;
+pub mod config;
+pub mod ensemble;
+pub mod error;
+mod event;
+pub mod frames;
+pub mod lattice;
+pub mod mask;
+pub mod model;
+pub mod molecule;
+pub mod output;
+pub mod protocol;
+#[cfg(feature = "python")]
+mod python;
+pub mod randomness;
+pub mod rates;
+pub mod region;
+pub mod simulation;
+pub mod summary;
+pub mod sweep;
+pub mod topology;
+pub mod units;
+pub mod variability;
+
+pub use checkpoint::Checkpoint;
+pub use error::{Error, Result};
+pub use lattice::{Geometry, Lattice};
+pub use model::Model;
+pub use output::Output;
+pub use protocol::{Command, Protocol, ProtocolError};
+pub use rates::Rates;
+pub use simulation::Simulation;
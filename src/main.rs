@@ -21,32 +21,31 @@
 // Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
 // -------------------------------------------------------------------------------------------------
 
-mod cell;
-mod commands;
-mod config;
-mod event;
-mod lattice;
-mod molecule;
-mod protocol;
-mod randomness;
-mod rates;
-mod simulation;
-mod units;
-
-use config::THREAD_STACK_SIZE;
-use lattice::Lattice;
-use protocol::Protocol;
-use randomness::initialize_generator;
-use rates::Rates;
+use vis_a_vis::checkpoint::Checkpoint;
+use vis_a_vis::config::THREAD_STACK_SIZE;
+use vis_a_vis::ensemble::write_ensemble_summary;
+use vis_a_vis::randomness::{derive_seeds, gen_seed_from_time, initialize_generator, parse_seed};
+use vis_a_vis::sweep::{write_results, Sweep};
+use vis_a_vis::{Error, Geometry, Lattice, Model, Output, Protocol, Rates, Result};
 
 use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
 
 fn print_usage_info() -> bool {
     if env::args().len() == 1 || env::args().any(|x| x == "-h" || x == "--help") {
         println!("Usage:");
         let exe_path = &env::args().collect::<Vec<_>>()[0];
         for invocation in [
-            [ exe_path, "[parameters JSON file] [protocol file] <-i|--images>"],
+            [ exe_path, "[parameters JSON file] [protocol file] <-i|--images> <-s|--seed SEED>"],
+            [ exe_path, "  <--width W> <--height H> <--occupancy F> <--boundary B> <--mask MASK>"],
+            [ exe_path, "  <-o|--output-dir DIR> <-p|--prefix PREFIX> <-f|--force> <--columnar>"],
+            [ exe_path, "  <--summary-every MINUTES> <-m|--model FILE> <--init-state CSV_FILE>"],
+            [ exe_path, "  <--checkpoint-every MINUTES> <--resume CHECKPOINT_FILE>"],
+            [ exe_path, "  <--replicates N> <--topology T>"],
+            [ exe_path, "[sweep JSON file] [protocol file] --sweep <options as above>"],
             [ exe_path, "[-h|--help]"],
             [ exe_path, "[-v|--version]"],
         ] {
@@ -66,23 +65,328 @@ fn print_version_info() -> bool {
     false
 }
 
-fn execute_protocol() -> bool {
+fn option_value<T: FromStr>(names: &[&str]) -> Result<Option<T>> {
+    let argv = env::args().collect::<Vec<String>>();
+    match argv.iter().position(|x| names.contains(&x.as_str())) {
+        None => Ok(None),
+        Some(i) => match argv.get(i + 1).map(|value_s| value_s.parse::<T>()) {
+            Some(Ok(value)) => Ok(Some(value)),
+            _ => Err(Error::InvalidParameter(format!("value of option {}", argv[i]))),
+        },
+    }
+}
+
+// what is needed to start a run (or each of its replicates)
+#[derive(Clone)]
+struct RunSetup {
+    parameters_file_path: String,
+    sweep_file_path: Option<String>,
+    protocol_file_path: String,
+    model_file_path: Option<String>,
+    init_state_file_path: Option<String>,
+    rates: Rates,
+    protocol: Protocol,
+    geometry: Geometry,
+    columnar_out: bool,
+}
+
+impl RunSetup {
+    fn save_manifest(&self, seed: u64, n_replicates: usize, output: &Output) -> Result<()> {
+        let manifest = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "parameters": self.parameters_file_path,
+            "sweep": self.sweep_file_path,
+            "protocol": self.protocol_file_path,
+            "model": self.model_file_path,
+            "init_state": self.init_state_file_path,
+            "seed": seed,
+            "replicates": n_replicates,
+        });
+        let manifest_file = File::create(output.file_path("manifest.json"))?;
+        serde_json::to_writer_pretty(manifest_file, &manifest)?;
+        Ok(())
+    }
+
+    fn start(&self, seed: u64, mut output: Output) -> Result<()> {
+        let mut generator = initialize_generator(seed);
+        let lattice = &mut Lattice::new(&self.geometry, &mut generator)?;
+        if let Some(init_state_file_path) = &self.init_state_file_path {
+            lattice.load_csv(&self.rates, init_state_file_path)?;
+        }
+        if Lattice::NEIGHS_TO_FILE {
+            lattice.save_neighbors(&output)?;
+        }
+        if self.columnar_out {
+            output.open_frames_file(lattice, &self.rates, None)?;
+        }
+        output.open_summary_file(&self.rates, None)?;
+        self.protocol.execute(lattice, &self.rates, &mut generator, &output)
+    }
+
+    fn resume(&self, checkpoint_path: PathBuf, mut output: Output) -> Result<()> {
+        let checkpoint = Checkpoint::load(checkpoint_path)?;
+        if self.columnar_out {
+            output.open_frames_file(&checkpoint.lattice, &self.rates, checkpoint.frames_len)?;
+        }
+        output.open_summary_file(&self.rates, checkpoint.summary_len)?;
+        self.protocol.resume(checkpoint, &self.rates, &output).map(|_| ())
+    }
+}
+
+// a run among many executed in parallel
+struct Job {
+    name: String,
+    setup: Arc<RunSetup>,
+    seed: u64,
+    output: Output,
+}
+
+// name of the i-th of n subdirectories of the given kind
+fn subdir_name(kind: &str, i: usize, n: usize) -> String {
+    format!("{}_{:0width$}", kind, i, width = (n - 1).to_string().len().max(3))
+}
+
+// Adds jobs of a run, whose output directory is prepared and has the manifest. Replicates of
+// the run go to its subdirectories. Returns paths to summaries of the replicates.
+fn add_jobs(
+    jobs: &mut Vec<Job>,
+    name: &str,
+    setup: &Arc<RunSetup>,
+    seed: u64,
+    n_replicates: usize,
+    output: &Output,
+    force: bool,
+) -> Result<Vec<PathBuf>> {
+    if n_replicates == 1 {
+        let summary_path = output.file_path("summary.csv");
+        let output = output.clone();
+        jobs.push(Job { name: name.to_string(), setup: setup.clone(), seed, output });
+        return Ok(vec![summary_path]);
+    }
+    let mut summary_paths = Vec::with_capacity(n_replicates);
+    for (replicate_i, replicate_seed) in derive_seeds(seed, n_replicates).into_iter().enumerate() {
+        let replicate_name = subdir_name("replicate", replicate_i, n_replicates);
+        let replicate_output = Output { dir: output.dir.join(&replicate_name), ..output.clone() };
+        replicate_output.prepare_dir(force)?;
+        setup.save_manifest(replicate_seed, 1, &replicate_output)?;
+        summary_paths.push(replicate_output.file_path("summary.csv"));
+        jobs.push(Job {
+            name: Path::new(name).join(replicate_name).display().to_string(),
+            setup: setup.clone(),
+            seed: replicate_seed,
+            output: replicate_output,
+        });
+    }
+    Ok(summary_paths)
+}
+
+// Executes jobs on all available cores, reporting the error of the first failed job, if any.
+fn execute_in_parallel(jobs: Vec<Job>) -> Result<()> {
+    let (errors_tx, errors_rx) = mpsc::channel::<(usize, Error)>();
+    let pool = threadpool::Builder::new()
+        .num_threads(num_cpus::get().min(jobs.len()))
+        .thread_stack_size(THREAD_STACK_SIZE)
+        .build();
+    for (job_i, job) in jobs.into_iter().enumerate() {
+        let errors = errors_tx.clone();
+        pool.execute(move || {
+            let result = job.setup.start(job.seed, job.output);
+            let status = if result.is_ok() { "done" } else { "failed" };
+            println!("{} (seed {}): {}", job.name, job.seed, status);
+            if let Err(err) = result {
+                errors.send((job_i, err)).unwrap_or(())
+            }
+        });
+    }
+    pool.join();
+    drop(errors_tx);
+    match errors_rx.iter().min_by_key(|(job_i, _)| *job_i) {
+        Some((_, err)) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// Runs replicates in parallel, each in its own subdirectory of the output directory, and then
+// gathers their summaries into the ensemble summary (see module ensemble).
+fn execute_replicates(
+    setup: RunSetup,
+    seed: u64,
+    n_replicates: usize,
+    output: &Output,
+    force: bool,
+) -> Result<()> {
+    let mut jobs = Vec::with_capacity(n_replicates);
+    let setup = Arc::new(setup);
+    let summary_paths = add_jobs(&mut jobs, "", &setup, seed, n_replicates, output, force)?;
+    execute_in_parallel(jobs)?;
+    let ensemble_summary_file = File::create(output.file_path("ensemble_summary.csv"))?;
+    write_ensemble_summary(&summary_paths, ensemble_summary_file)
+}
+
+// Runs all points of the sweep (each possibly replicated) in parallel, each in its own
+// subdirectory of the output directory, with parameters of the point saved in it. Summaries of
+// all runs are gathered into a single table (see module sweep).
+fn execute_sweep(
+    sweep: &Sweep,
+    setup: RunSetup,
+    seed: u64,
+    n_replicates: usize,
+    output: &Output,
+    force: bool,
+) -> Result<()> {
+    let points = sweep.points();
+    let (mut jobs, mut runs) = (Vec::new(), Vec::new());
+    let mut ensembles = Vec::new(); // ensemble summary paths and paths to summaries of replicates
+    let point_seeds = derive_seeds(seed, points.len());
+    for ((point_i, point), point_seed) in points.iter().enumerate().zip(point_seeds) {
+        let name = subdir_name("point", point_i, points.len());
+        let point_output = Output { dir: output.dir.join(&name), ..output.clone() };
+        point_output.prepare_dir(force)?;
+        let parameters_file_path = point_output.file_path("parameters.json");
+        let parameters_file = File::create(&parameters_file_path)?;
+        serde_json::to_writer_pretty(parameters_file, &sweep.parameters_at(point))?;
+        let point_setup = Arc::new(RunSetup {
+            parameters_file_path: parameters_file_path.display().to_string(),
+            sweep_file_path: None,
+            rates: Rates { model: setup.rates.model.clone(), ..sweep.rates_at(point)? },
+            ..setup.clone()
+        });
+        point_setup.save_manifest(point_seed, n_replicates, &point_output)?;
+        let (setup, seed, output) = (&point_setup, point_seed, &point_output);
+        let summary_paths = add_jobs(&mut jobs, &name, setup, seed, n_replicates, output, force)?;
+        for (replicate_i, summary_path) in summary_paths.iter().enumerate() {
+            runs.push((point_i, replicate_i, point.clone(), summary_path.clone()));
+        }
+        if n_replicates > 1 {
+            ensembles.push((point_output.file_path("ensemble_summary.csv"), summary_paths));
+        }
+    }
+    execute_in_parallel(jobs)?;
+    for (ensemble_summary_path, summary_paths) in ensembles {
+        write_ensemble_summary(&summary_paths, File::create(ensemble_summary_path)?)?;
+    }
+    write_results(&sweep.fields(), &runs, File::create(output.file_path("sweep_results.csv"))?)
+}
+
+fn execute_protocol_() -> Result<()> {
     let argv = env::args().collect::<Vec<String>>();
-    let rates = Rates::from_json_file(&argv[1]);
-    let protocol = Protocol::from_text_file(&argv[2]);
-    let images_out = env::args().any(|x| x == "-i" || x == "--images");
+    // (with a sweep, the first argument is the sweep file, which names the parameters file)
+    let sweep = match env::args().any(|x| x == "--sweep") {
+        true => Some(Sweep::from_json_file(&argv[1])?),
+        false => None,
+    };
+    let parameters_file_path = match &sweep {
+        Some(sweep) => sweep.parameters.display().to_string(),
+        None => argv[1].clone(),
+    };
+    let mut rates = Rates::from_json_file(&parameters_file_path)?;
+    let model_file_path = option_value::<String>(&["-m", "--model"])?;
+    if let Some(model_file_path) = &model_file_path {
+        rates.model = Some(Arc::new(Model::from_json_file(model_file_path, &rates)?));
+    }
+    rates.cytokine_secretors()?;
+    let protocol = Protocol::from_text_file(&argv[2])?;
+    let mut output = Output {
+        images: env::args().any(|x| x == "-i" || x == "--images"),
+        ..Output::default()
+    };
+    if let Some(prefix) = option_value(&["-p", "--prefix"])? {
+        output.prefix = prefix;
+    }
+    let mut geometry = Geometry::from_json_file(&parameters_file_path)?;
+    if let Some(width) = option_value(&["--width"])? {
+        geometry.width = width;
+    }
+    if let Some(height) = option_value(&["--height"])? {
+        geometry.height = height;
+    }
+    if let Some(occupancy) = option_value(&["--occupancy"])? {
+        geometry.occupancy = occupancy;
+    }
+    if let Some(boundary) = option_value(&["--boundary"])? {
+        geometry.boundary = boundary;
+    }
+    if let Some(mask) = option_value(&["--mask"])? {
+        geometry.mask = Some(mask);
+    }
+    if let Some(topology) = option_value(&["--topology"])? {
+        geometry.topology = topology;
+    }
+    let seed = match option_value::<String>(&["-s", "--seed"])? {
+        Some(seed_s) => parse_seed(&seed_s)
+            .map_err(|_| Error::InvalidParameter(format!("seed {}", seed_s)))?,
+        None => gen_seed_from_time(),
+    };
+    let n_replicates = option_value::<usize>(&["--replicates"])?.unwrap_or(1);
+    if n_replicates == 0 {
+        return Err(Error::InvalidParameter("number of replicates 0".to_string()));
+    }
+    let resumed_from = option_value::<PathBuf>(&["--resume"])?;
+    if resumed_from.is_some() && (n_replicates > 1 || sweep.is_some()) {
+        return Err(Error::InvalidParameter(
+            "resuming parallel runs (each run is to be resumed separately)".to_string(),
+        ));
+    }
+    // (a resumed run continues writing to the directory of the interrupted run)
+    let force = env::args().any(|x| x == "-f" || x == "--force") || resumed_from.is_some();
+    if let Some(dir) = option_value(&["-o", "--output-dir"])? {
+        output.dir = dir;
+        output.prepare_dir(force)?;
+    }
+    if let Some(interval) = option_value::<f64>(&["--summary-every"])? {
+        if interval <= 0. {
+            return Err(Error::InvalidParameter(format!("summary interval {}", interval)));
+        }
+        output.summary_interval = Some(interval);
+    }
+    if let Some(interval) = option_value::<f64>(&["--checkpoint-every"])? {
+        if interval <= 0. {
+            return Err(Error::InvalidParameter(format!("checkpoint interval {}", interval)));
+        }
+        output.checkpoint_interval = Some(interval);
+    }
+    let setup = RunSetup {
+        parameters_file_path,
+        sweep_file_path: sweep.as_ref().map(|_| argv[1].clone()),
+        protocol_file_path: argv[2].clone(),
+        model_file_path,
+        init_state_file_path: option_value::<String>(&["--init-state"])?,
+        rates,
+        protocol,
+        geometry,
+        columnar_out: env::args().any(|x| x == "--columnar"),
+    };
+    if resumed_from.is_none() {
+        println!("Seed: {}", seed);
+        setup.save_manifest(seed, n_replicates, &output)?;
+    }
+    if let Some(sweep) = sweep {
+        output.quiet = true;
+        return execute_sweep(&sweep, setup, seed, n_replicates, &output, force);
+    }
+    if n_replicates > 1 {
+        output.quiet = true;
+        return execute_replicates(setup, seed, n_replicates, &output, force);
+    }
 
     std::thread::Builder::new()
         .name("protocol_execution".into())
         .stack_size(THREAD_STACK_SIZE)
-        .spawn(move || {
-            let mut generator = initialize_generator();
-            let mut lattice = Lattice::new(&mut generator);
-            protocol.execute(&mut lattice, &rates, &mut generator, images_out);
-        })
-        .expect("☠ @ protocol_execution thread")
+        .spawn(move || -> Result<()> {
+            match resumed_from {
+                Some(checkpoint_path) => setup.resume(checkpoint_path, output),
+                None => setup.start(seed, output),
+            }
+        })?
         .join()
-        .expect("☠ @ threads join");
+        .expect("☠ @ threads join")
+}
+
+fn execute_protocol() -> bool {
+    if let Err(err) = execute_protocol_() {
+        eprintln!("☠ {}", err);
+        std::process::exit(1)
+    }
     true
 }
 
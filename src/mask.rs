@@ -0,0 +1,192 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Masks of the domain of the lattice, marking each node as outside the domain (with neither a
+// cell nor medium), vacant (with medium only), or to be occupied by a cell (with probability
+// given by the occupancy, see Geometry). A mask is given either as a shape, "circle r=45" (nodes
+// within the given distance from the center of the lattice, i.e., of the bounding box of node
+// positions, are occupied, others are outside; distances as in module region), or as the path of
+// a grayscale PGM or PNG image of exactly width × height pixels, in which pixel (x, y)
+// corresponds to node x + y * width, and black marks nodes outside the domain, gray vacant
+// nodes, and white occupied nodes.
+
+use crate::error::{Error, Result};
+
+use std::fs::{self, File};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Outside,
+    Vacant,
+    Occupied,
+}
+
+impl NodeKind {
+    // (of gray level, from 0 for black to 1 for white)
+    fn of_level(level: f64) -> Self {
+        match level {
+            l if l < 1. / 3. => NodeKind::Outside,
+            l if l < 2. / 3. => NodeKind::Vacant,
+            _ => NodeKind::Occupied,
+        }
+    }
+}
+
+// Shapes are recognized by their name, other masks are paths of images.
+pub fn is_shape(mask: &str) -> bool {
+    mask.trim_start().starts_with("circle ")
+}
+
+// (for nodes at given positions in the plane, see Lattice::position)
+pub fn node_kinds(
+    mask: &str,
+    width: usize,
+    height: usize,
+    positions: &[(f64, f64)],
+) -> Result<Vec<NodeKind>> {
+    if is_shape(mask) {
+        return shape_node_kinds(mask, positions);
+    }
+    let path = Path::new(mask);
+    let (image_width, image_height, levels) = match path.extension().and_then(|e| e.to_str()) {
+        Some("pgm") => read_pgm(path)?,
+        Some("png") => read_png(path)?,
+        _ => return Err(Error::InvalidParameter(format!("mask {:?} of unknown format", mask))),
+    };
+    if (image_width, image_height) != (width, height) {
+        return Err(Error::InvalidParameter(format!(
+            "mask {:?} of {}×{} pixels for a lattice of {}×{} nodes",
+            mask, image_width, image_height, width, height
+        )));
+    }
+    Ok(levels.into_iter().map(NodeKind::of_level).collect())
+}
+
+fn shape_node_kinds(shape: &str, positions: &[(f64, f64)]) -> Result<Vec<NodeKind>> {
+    let invalid = || Err(Error::InvalidParameter(format!("mask shape {:?}", shape)));
+    let r = match shape.split_whitespace().collect::<Vec<_>>()[..] {
+        ["circle", r] => match r.strip_prefix("r=").map(str::parse::<f64>) {
+            Some(Ok(r)) if r >= 0. => r,
+            _ => return invalid(),
+        },
+        _ => return invalid(),
+    };
+    let extent = |coordinate: fn(&(f64, f64)) -> f64| {
+        let coordinates = positions.iter().map(coordinate);
+        let (min, max) = coordinates.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), c| {
+            (min.min(c), max.max(c))
+        });
+        (min + max) / 2.
+    };
+    let center = (extent(|p| p.0), extent(|p| p.1));
+    let kind = |&(x, y): &(f64, f64)| match (x - center.0).hypot(y - center.1) <= r {
+        true => NodeKind::Occupied,
+        false => NodeKind::Outside,
+    };
+    Ok(positions.iter().map(kind).collect())
+}
+
+// Reads a PGM image (in the plain or raw format), returning its width, height, and gray levels
+// of pixels (from 0 to 1), row after row.
+fn read_pgm(path: &Path) -> Result<(usize, usize, Vec<f64>)> {
+    let invalid = |message: &str| {
+        Err(Error::Image(format!("{}: {}", path.display(), message)))
+    };
+    let bytes = fs::read(path)?;
+    // header: magic number, width, height, maximal gray level (possibly with comments)
+    let (mut fields, mut pos) = (Vec::new(), 0);
+    while fields.len() < 4 && pos < bytes.len() {
+        match bytes[pos] {
+            b'#' => {
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    pos += 1
+                }
+            }
+            b if b.is_ascii_whitespace() => pos += 1,
+            _ => {
+                let start = pos;
+                while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+                    pos += 1
+                }
+                fields.push(String::from_utf8_lossy(&bytes[start..pos]).to_string())
+            }
+        }
+    }
+    let numbers = fields.iter().skip(1).map(|f| f.parse::<usize>()).collect::<Vec<_>>();
+    let (width, height, max_level) = match numbers[..] {
+        [Ok(width), Ok(height), Ok(max_level)] if max_level > 0 && max_level < 65536 => {
+            (width, height, max_level)
+        }
+        _ => return invalid("invalid header"),
+    };
+    let levels: Vec<usize> = match fields[0].as_str() {
+        "P2" => {
+            let text = String::from_utf8_lossy(&bytes[pos..]);
+            match text.split_whitespace().map(str::parse::<usize>).collect() {
+                Ok(levels) => levels,
+                Err(_) => return invalid("invalid gray level"),
+            }
+        }
+        "P5" => {
+            // (a single whitespace character separates the header from the data)
+            let data = &bytes[(pos + 1).min(bytes.len())..];
+            let level = |bytes: &[u8]| bytes.iter().fold(0, |l, &b| 256 * l + b as usize);
+            data.chunks(if max_level < 256 { 1 } else { 2 }).map(level).collect()
+        }
+        _ => return invalid("not a PGM image"),
+    };
+    if levels.len() < width * height {
+        return invalid("too few pixels");
+    }
+    let levels = levels.iter().take(width * height).map(|&l| l as f64 / max_level as f64);
+    Ok((width, height, levels.collect()))
+}
+
+// Reads a PNG image, returning its width, height, and gray levels of pixels (from 0 to 1, mean
+// over color channels), row after row.
+fn read_png(path: &Path) -> Result<(usize, usize, Vec<f64>)> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let n_channels = info.color_type.samples();
+    let n_colors = match info.color_type {
+        png::ColorType::GrayscaleAlpha | png::ColorType::Rgba => n_channels - 1,
+        _ => n_channels,
+    };
+    let (width, height) = (info.width as usize, info.height as usize);
+    let mut levels = Vec::with_capacity(width * height);
+    for row in buffer[..info.buffer_size()].chunks(info.line_size).take(height) {
+        for pixel in row.chunks(n_channels).take(width) {
+            let sum = pixel[..n_colors].iter().map(|&c| c as f64).sum::<f64>();
+            levels.push(sum / (255. * n_colors as f64))
+        }
+    }
+    Ok((width, height, levels))
+}
+
+#[test]
+fn test_mask_node_kinds() {
+    use crate::topology::Topology;
+    let positions = (0..7 * 5).map(|i| ((i % 7) as f64, (i / 7) as f64)).collect::<Vec<_>>();
+    let hexagonal = positions.iter().map(|&p| Topology::Hexagonal.position(p)).collect::<Vec<_>>();
+    let kinds = node_kinds("circle r=2", 7, 5, &hexagonal).unwrap();
+    assert_eq!(kinds[3 + 2 * 7], NodeKind::Occupied); // (the center)
+    assert_eq!(kinds[0], NodeKind::Outside);
+    assert_eq!(kinds.iter().filter(|&&k| k == NodeKind::Occupied).count(), 19);
+    assert!(node_kinds("circle 2", 7, 5, &hexagonal).is_err());
+    let kinds = node_kinds("circle r=1.5", 7, 5, &positions).unwrap(); // (of the square lattice)
+    assert_eq!(kinds.iter().filter(|&&k| k == NodeKind::Occupied).count(), 9);
+
+    let path = std::env::temp_dir().join(format!("visavis-mask-{}.pgm", std::process::id()));
+    fs::write(&path, "P2\n# a comment\n3 2\n255\n0 128 255\n255 255 0\n").unwrap();
+    let kinds = node_kinds(path.to_str().unwrap(), 3, 2, &[]).unwrap();
+    fs::remove_file(&path).unwrap();
+    use NodeKind::{Occupied, Outside, Vacant};
+    assert_eq!(kinds, [Outside, Vacant, Occupied, Occupied, Occupied, Outside]);
+    assert!(node_kinds(path.to_str().unwrap(), 3, 2, &[]).is_err());
+}
@@ -0,0 +1,409 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Reaction network given in a model file (JSON), as an alternative to the built-in wiring of
+// regulatory interactions (in Simulation::set_cell_events_props).
+//
+// Species are mapped, in order, onto molecule slots of cells, so a model may have at most
+// MAX_MOLECULE_SPECIES species. Commands that infect cells or wash out the inoculum find the
+// species "Vinf" and "VRNA" by name, as do images. Each species has a level range 0..=max and
+// an activity threshold. A reaction increases or decreases the level of a species by one, or
+// kills the cell. If all its conditions hold, its propensity is the rate constant times the
+// product of rate-law factors, plus an optional basal term. Constants are given either as
+// numbers or as names of parameters from the parameters JSON file, so that they are looked up
+// in Rates at run time; a rate constant given by name varies among cells if so given in the
+// parameters file (see module variability).
+
+use crate::cell::Cell;
+use crate::error::{Error, Result};
+use crate::lattice::Lattice;
+use crate::molecule::{Mol, MAX_MOLECULE_SPECIES, MOLECULE_NAMES};
+use crate::rates::Rates;
+
+use serde::Deserialize;
+use serde_json::{from_str, Value};
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Model {
+    pub species: Vec<Species>,
+    #[serde(default)]
+    pub secreted: Option<String>, // species which, when active, makes the cell secrete IFN
+    #[serde(default)]
+    pub released: Option<String>, // species which, when active, makes the cell release virions
+    pub reactions: Vec<Reaction>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Species {
+    pub name: String,
+    pub max: u8,
+    pub active: u8, // activity threshold
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Reaction {
+    pub name: String,
+    pub effect: Effect,
+    pub rate: Constant,
+    #[serde(default)]
+    pub when: Vec<Condition>,
+    #[serde(default)]
+    pub law: Vec<Factor>,
+    #[serde(default)]
+    pub basal: Option<Constant>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    Increase(String), // {"increase": "Vrna"}, possible only below max level
+    Decrease(String), // {"decrease": "Vrna"}, possible only above zero
+    Die,              // "die"
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Constant {
+    Value(f64),        // 0.5
+    Parameter(String), // "vrna_incr"
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Active(String),   // {"active": "Vinf"}
+    Inactive(String), // {"inactive": "Vinf"}
+    Zero(String),     // {"zero": "Vrna"}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Factor {
+    Count(String),                          // x
+    ActiveNeighbors(String),                // weighted number of neighbors with active species
+    Inhibition { by: String, k: Constant }, // 1/(k x + 1)
+    Promotion { by: String, k: Constant },  // k x + 1
+    Saturation {
+        // x^n/(k^n + x^n), where x is a species level, or "IFNe" or another cytokine (see
+        // Rates::cytokines) in the lower subcompartment
+        of: String,
+        k: Constant,
+        #[serde(default = "Factor::default_hill_coefficient")]
+        n: f64,
+    },
+    FreeVirions, // "free_virions", in the medium above the cell (see Rates::free_virions)
+}
+
+impl Factor {
+    fn default_hill_coefficient() -> f64 {
+        1.
+    }
+}
+
+impl Model {
+    // The model is checked against parameters, as its constants may refer to them.
+    pub fn from_json_file<P: AsRef<Path>>(model_filename: P, rates: &Rates) -> Result<Self> {
+        let contents = fs::read_to_string(model_filename)?;
+        let model: Model = from_str(&contents)?;
+        model.bind(rates)?;
+        Ok(model)
+    }
+
+    fn species_index(&self, name: &str) -> Result<usize> {
+        self.species
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| Error::InvalidParameter(format!("model: unknown species {:?}", name)))
+    }
+
+    // Resolves names of species and parameters into a network ready for simulation.
+    pub fn bind(&self, rates: &Rates) -> Result<Network> {
+        let invalid = |message: String| Error::InvalidParameter(["model: ", &message].concat());
+        if self.species.len() > MAX_MOLECULE_SPECIES {
+            return Err(invalid(format!("more than {} species", MAX_MOLECULE_SPECIES)));
+        }
+        let (mut max, mut active) = ([0; MAX_MOLECULE_SPECIES], [0; MAX_MOLECULE_SPECIES]);
+        for (mi, species) in self.species.iter().enumerate() {
+            if self.species[..mi].iter().any(|s| s.name == species.name) {
+                return Err(invalid(format!("duplicate species {:?}", species.name)));
+            }
+            if species.max == 0 || species.active == 0 || species.active > species.max {
+                return Err(invalid(format!("level bounds of species {:?}", species.name)));
+            }
+            (max[mi], active[mi]) = (species.max, species.active)
+        }
+
+        let parameters = serde_json::to_value(rates)?;
+        let constant = |c: &Constant| match c {
+            Constant::Value(value) => Ok(*value),
+            Constant::Parameter(name) => parameters
+                .get(name)
+                .and_then(Value::as_f64)
+                .ok_or_else(|| invalid(format!("unknown parameter {:?}", name))),
+        };
+
+        let mut network = Network {
+            reactions: Vec::with_capacity(self.reactions.len()),
+            max,
+            active,
+            secreted: self.secreted.as_deref().map(|s| self.species_index(s)).transpose()?,
+            released: self.released.as_deref().map(|s| self.species_index(s)).transpose()?,
+            sensed_by_neighbors: [false; MAX_MOLECULE_SPECIES],
+        };
+        for reaction in self.reactions.iter() {
+            let change = match &reaction.effect {
+                Effect::Increase(s) => Change::Increase(self.species_index(s)?),
+                Effect::Decrease(s) => Change::Decrease(self.species_index(s)?),
+                Effect::Die => Change::Die,
+            };
+            let mut when = Vec::with_capacity(reaction.when.len());
+            for condition in reaction.when.iter() {
+                when.push(match condition {
+                    Condition::Active(s) => Check::Active(self.species_index(s)?),
+                    Condition::Inactive(s) => Check::Inactive(self.species_index(s)?),
+                    Condition::Zero(s) => Check::Zero(self.species_index(s)?),
+                })
+            }
+            let mut law = Vec::with_capacity(reaction.law.len());
+            for factor in reaction.law.iter() {
+                law.push(match factor {
+                    Factor::Count(s) => Term::Count(self.species_index(s)?),
+                    Factor::ActiveNeighbors(s) => {
+                        let mi = self.species_index(s)?;
+                        network.sensed_by_neighbors[mi] = true;
+                        Term::ActiveNeighbors(mi)
+                    }
+                    Factor::Inhibition { by, k } => {
+                        Term::Inhibition(self.species_index(by)?, constant(k)?)
+                    }
+                    Factor::Promotion { by, k } => {
+                        Term::Promotion(self.species_index(by)?, constant(k)?)
+                    }
+                    Factor::Saturation { of, k, n } => {
+                        let cytokine_i = rates.cytokines.iter().position(|c| &c.name == of);
+                        let of = match cytokine_i {
+                            _ if of == "IFNe" => Input::Ifne,
+                            Some(ci) => Input::Cytokine(ci),
+                            None => Input::Species(self.species_index(of)?),
+                        };
+                        Term::Saturation(of, constant(k)?, *n)
+                    }
+                    Factor::FreeVirions => Term::FreeVirions,
+                })
+            }
+            let rate = constant(&reaction.rate)?;
+            let rate_parameter = match &reaction.rate {
+                Constant::Parameter(name) => Some(name.clone()),
+                Constant::Value(_) => None,
+            };
+            let basal = reaction.basal.as_ref().map(constant).transpose()?.unwrap_or(0.);
+            if rate < 0. || basal < 0. {
+                return Err(invalid(format!("negative rate of reaction {:?}", reaction.name)));
+            }
+            let medium_dependent = law.iter().any(|t| {
+                matches!(
+                    t,
+                    Term::Saturation(Input::Ifne | Input::Cytokine(_), _, _) | Term::FreeVirions
+                )
+            });
+            let reaction = BoundReaction {
+                change,
+                rate,
+                rate_parameter,
+                when,
+                law,
+                basal,
+                medium_dependent,
+            };
+            network.reactions.push(reaction)
+        }
+        Ok(network)
+    }
+}
+
+// names of species, given by the model if present
+pub fn species_names(model: Option<&Model>) -> Vec<&str> {
+    match model {
+        Some(model) => model.species.iter().map(|s| s.name.as_str()).collect(),
+        None => MOLECULE_NAMES.to_vec(),
+    }
+}
+
+// slot of a built-in molecule, found by name among species of the model if present
+pub fn species_slot(model: Option<&Model>, m: Mol) -> Option<usize> {
+    species_names(model).iter().position(|&name| name == MOLECULE_NAMES[m as usize])
+}
+
+// maximum level of a species (given by its slot index), according to the model if present
+pub fn max_level(model: Option<&Model>, mi: usize) -> u8 {
+    match model {
+        Some(model) => model.species[mi].max,
+        None => Cell::MAX.molecules[mi],
+    }
+}
+
+// whether a species (given by its slot index) is active, according to the model if present
+pub fn is_active(model: Option<&Model>, mi: usize, ms: &[u8]) -> bool {
+    match model {
+        Some(model) => ms[mi] >= model.species[mi].active,
+        None => ms[mi] >= Cell::ACT.molecules[mi],
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    Increase(usize),
+    Decrease(usize),
+    Die,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Check {
+    Active(usize),
+    Inactive(usize),
+    Zero(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Input {
+    Species(usize),
+    Ifne,
+    Cytokine(usize), // (other than IFNe)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Term {
+    Count(usize),
+    ActiveNeighbors(usize),
+    Inhibition(usize, f64),
+    Promotion(usize, f64),
+    Saturation(Input, f64, f64), // (x; k; n)
+    FreeVirions,
+}
+
+#[derive(Debug, Clone)]
+struct BoundReaction {
+    change: Change,
+    rate: f64,
+    rate_parameter: Option<String>, // (by which the rate was given, see Kinetics::bind)
+    when: Vec<Check>,
+    law: Vec<Term>,
+    basal: f64,
+    medium_dependent: bool, // on IFNe or free virions
+}
+
+// model with species and parameters resolved
+#[derive(Debug, Clone)]
+pub struct Network {
+    reactions: Vec<BoundReaction>,
+    max: [u8; MAX_MOLECULE_SPECIES],
+    active: [u8; MAX_MOLECULE_SPECIES],
+    secreted: Option<usize>,
+    released: Option<usize>,
+    sensed_by_neighbors: [bool; MAX_MOLECULE_SPECIES], // (changes require updating neighbors)
+}
+
+impl Network {
+    pub fn n_events(&self) -> usize {
+        self.reactions.len()
+    }
+
+    // names of parameters by which rates of reactions were given, in the order of events
+    pub fn rate_parameters(&self) -> impl Iterator<Item = Option<&str>> {
+        self.reactions.iter().map(|reaction| reaction.rate_parameter.as_deref())
+    }
+
+    pub fn is_medium_dependent(&self, event_i: usize) -> bool {
+        self.reactions[event_i].medium_dependent
+    }
+
+    #[inline]
+    pub fn secretes_ifn(&self, ms: &[u8]) -> bool {
+        self.secreted.is_some_and(|mi| ms[mi] >= self.active[mi])
+    }
+
+    #[inline]
+    pub fn releases_virions(&self, ms: &[u8]) -> bool {
+        self.released.is_some_and(|mi| ms[mi] >= self.active[mi])
+    }
+
+    // (the rate factor is that of the cell, if the rate constant varies among cells)
+    pub fn propensity(
+        &self,
+        event_i: usize,
+        lattice: &Lattice,
+        cell_i: usize,
+        rate_factor: f64,
+    ) -> f64 {
+        let reaction = &self.reactions[event_i];
+        let ms = &lattice.cells[cell_i].molecules;
+        let possible = match reaction.change {
+            Change::Increase(mi) => ms[mi] < self.max[mi],
+            Change::Decrease(mi) => ms[mi] > 0,
+            Change::Die => true,
+        };
+        let conditions_hold = reaction.when.iter().all(|check| match *check {
+            Check::Active(mi) => ms[mi] >= self.active[mi],
+            Check::Inactive(mi) => ms[mi] < self.active[mi],
+            Check::Zero(mi) => ms[mi] == 0,
+        });
+        if !(possible && conditions_hold) {
+            return 0.;
+        }
+        let mut rate = reaction.rate * rate_factor;
+        for term in reaction.law.iter() {
+            rate *= match *term {
+                Term::Count(mi) => ms[mi] as f64,
+                Term::ActiveNeighbors(mi) => lattice.neighborhoods
+                    .weighted(cell_i)
+                    .filter(|&(ni, _)| lattice.cells[ni].molecules[mi] >= self.active[mi])
+                    .map(|(_, weight)| weight)
+                    .sum(),
+                Term::Inhibition(mi, k) => 1. / (ms[mi] as f64 * k + 1.),
+                Term::Promotion(mi, k) => ms[mi] as f64 * k + 1.,
+                Term::Saturation(of, k, n) => {
+                    let x = match of {
+                        Input::Species(mi) => ms[mi] as f64,
+                        Input::Ifne => lattice.cytokines[cell_i][0],
+                        Input::Cytokine(ci) => lattice.other_cytokine(ci, cell_i)[0],
+                    };
+                    if n == 1. { x / (k + x) } else { x.powf(n) / (k.powf(n) + x.powf(n)) }
+                }
+                Term::FreeVirions => lattice.virions[cell_i],
+            }
+        }
+        rate + reaction.basal
+    }
+
+    // Executes the event, returning indices of cells whose propensities have to be updated.
+    pub fn occur(&self, event_i: usize, lattice: &mut Lattice, cell_i: usize) -> Vec<usize> {
+        let cell = &mut lattice.cells[cell_i];
+        let changed_mi = match self.reactions[event_i].change {
+            Change::Increase(mi) => {
+                cell.molecules[mi] += 1;
+                Some(mi)
+            }
+            Change::Decrease(mi) => {
+                cell.molecules[mi] -= 1;
+                Some(mi)
+            }
+            Change::Die => {
+                cell.alive = false;
+                cell.molecules.iter_mut().for_each(|x| *x = 0);
+                None
+            }
+        };
+        let mut cells = vec![cell_i];
+        if changed_mi.is_none_or(|mi| self.sensed_by_neighbors[mi]) {
+            cells.extend_from_slice(&lattice.neighborhoods[cell_i])
+        }
+        cells
+    }
+}
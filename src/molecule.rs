@@ -3,6 +3,7 @@
 // Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
 // Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
 
+#[derive(Clone, Copy)]
 pub enum Mol {
     Vinf,  // viral infective particles (presence of the just-entered virus)
     Vrna,  // viral RNA
@@ -14,3 +15,10 @@ pub enum Mol {
 }
 
 pub const N_MOLECULE_SPECIES: usize = 7;
+
+// slots of molecules in cells, of which built-in molecules take the first N_MOLECULE_SPECIES
+// (the others are used by models, see module model)
+pub const MAX_MOLECULE_SPECIES: usize = 16;
+
+pub const MOLECULE_NAMES: [&str; N_MOLECULE_SPECIES] =
+    ["Vinf", "VRNA", "Vprot", "pIRF3", "IFNi", "pSTAT", "ISG"];
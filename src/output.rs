@@ -0,0 +1,126 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+use crate::error::{Error, Result};
+use crate::frames::FramesWriter;
+use crate::lattice::Lattice;
+use crate::rates::Rates;
+use crate::summary::SummaryWriter;
+
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// where and what output files are written
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub dir: PathBuf,
+    pub prefix: String, // prepended to every output file name
+    pub images: bool,   // whether PNG images are generated in addition to CSV files
+    pub frames: Option<Arc<Mutex<FramesWriter<File>>>>, // if set, replaces CSV files
+    pub summary: Option<Arc<Mutex<SummaryWriter<File>>>>, // population-level time course
+    pub summary_interval: Option<f64>, // if not set, summary is sampled when files are output
+    pub checkpoint_interval: Option<f64>, // if set, runs are checkpointed (see module checkpoint)
+    pub quiet: bool, // whether printing of progress is suppressed (e.g., in parallel runs)
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            dir: PathBuf::from("."),
+            prefix: String::new(),
+            images: false,
+            frames: None,
+            summary: None,
+            summary_interval: None,
+            checkpoint_interval: None,
+            quiet: false,
+        }
+    }
+}
+
+impl Output {
+    pub fn file_path(&self, file_name: &str) -> PathBuf {
+        self.dir.join([&self.prefix, file_name].concat())
+    }
+
+    // Path of a state file saved or loaded by the protocol: relative paths are resolved against
+    // the output directory, with the prefix prepended to the file name only.
+    pub fn state_path(&self, path: &Path) -> PathBuf {
+        match path.file_name() {
+            Some(file_name) if path.is_relative() => {
+                let file_name = [self.prefix.as_str(), &file_name.to_string_lossy()].concat();
+                self.dir.join(path.with_file_name(file_name))
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+
+    // Creates the output directory if needed. An existing directory is required to be empty,
+    // unless overwriting of its contents is forced.
+    pub fn prepare_dir(&self, force: bool) -> Result<()> {
+        if self.dir.is_dir() && !force && fs::read_dir(&self.dir)?.next().is_some() {
+            return Err(Error::InvalidParameter(format!(
+                "output directory {} is not empty (use --force to overwrite)",
+                self.dir.display()
+            )));
+        }
+        Ok(fs::create_dir_all(&self.dir)?)
+    }
+
+    // Opens a file for appending when continuing a resumed run, otherwise creates it anew.
+    // A resumed file is first cut to its length at the checkpoint, so that output written after
+    // the checkpoint (possibly only in part, if the run was killed) is not repeated.
+    fn open_file(&self, file_name: &str, resumed_len: Option<u64>) -> Result<(File, bool)> {
+        let path = self.file_path(file_name);
+        match resumed_len {
+            Some(len) if path.is_file() => {
+                let file = OpenOptions::new().append(true).open(&path)?;
+                if file.metadata()?.len() < len {
+                    return Err(Error::InvalidParameter(format!(
+                        "{} is shorter than at the checkpoint",
+                        path.display()
+                    )));
+                }
+                file.set_len(len)?;
+                Ok((file, true))
+            }
+            _ => Ok((File::create(path)?, false)),
+        }
+    }
+
+    // Byte lengths of the frames file and of the summary file, if written (for checkpoints).
+    pub fn file_lengths(&self) -> Result<(Option<u64>, Option<u64>)> {
+        let len = |file_name| fs::metadata(self.file_path(file_name)).map(|m| m.len());
+        let frames_len = self.frames.as_ref().map(|_| len("frames.bin")).transpose()?;
+        let summary_len = self.summary.as_ref().map(|_| len("summary.csv")).transpose()?;
+        Ok((frames_len, summary_len))
+    }
+
+    // Turns on columnar output: from now on, frames are appended to a single file.
+    pub fn open_frames_file(
+        &mut self,
+        lattice: &Lattice,
+        rates: &Rates,
+        resumed_len: Option<u64>,
+    ) -> Result<()> {
+        let frames = match self.open_file("frames.bin", resumed_len)? {
+            (frames_file, true) => FramesWriter::appending(frames_file, lattice, rates),
+            (frames_file, false) => FramesWriter::new(frames_file, lattice, rates)?,
+        };
+        self.frames = Some(Arc::new(Mutex::new(frames)));
+        Ok(())
+    }
+
+    // Turns on the population-level summary (see module summary).
+    pub fn open_summary_file(&mut self, rates: &Rates, resumed_len: Option<u64>) -> Result<()> {
+        let summary = match self.open_file("summary.csv", resumed_len)? {
+            (summary_file, true) => SummaryWriter::appending(summary_file, rates),
+            (summary_file, false) => SummaryWriter::new(summary_file, rates)?,
+        };
+        self.summary = Some(Arc::new(Mutex::new(summary)));
+        Ok(())
+    }
+}
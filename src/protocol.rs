@@ -3,92 +3,654 @@
 // Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
 // Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
 
-use std::fs::File;
-use std::io::{self, BufRead};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
 use std::num::ParseFloatError;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use rand::rngs::StdRng;
-
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{char, digit1, multispace1},
-    combinator::{map_res, opt, recognize},
+    bytes::complete::{tag, take_while1},
+    character::complete::{char, digit1, multispace1, one_of},
+    combinator::{map, map_res, opt, recognize, verify},
     error::ErrorKind,
     number::complete::double,
-    sequence::{delimited, pair, separated_pair, terminated, tuple},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult,
 };
 
-use crate::commands::{add_virus, remove_ifne, run_simulation, run_simulation_quietly, set_upper_ifne};
+use crate::checkpoint::Checkpoint;
+use crate::commands::{add_upper_ifne, add_virus, add_virus_in, remove_ifne, run_simulation_until};
+use crate::commands::{dilute_ifne, remove_free_virus, remove_ifne_fraction, Layer};
+use crate::commands::{set_upper_ifne, set_upper_ifne_in};
+use crate::error::{Error, Result};
 use crate::lattice::Lattice;
+use crate::output::Output;
+use crate::randomness::Generator;
 use crate::rates::Rates;
+use crate::region::Region;
+use crate::simulation::{Kinetics, RunProgress};
 use crate::units::{MIN, HOUR, DAY, conversion};
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run { tspan: (f64, f64), every: Option<f64> }, // "run 0h...1d [2h]", or "run 0h...1d []"
+    SetIfn(f64),                                   // "=IFN 1000 U/ml"
+    AddIfn(f64),                                   // "+IFN 1000 U/ml"
+    RemoveIfn,                                     // "!IFN"
+    RemoveIfnFraction(f64, Layer),                 // "-IFN 0.5 upper" (or "lower", or "both")
+    DiluteIfn(f64),                                // "/IFN 10"
+    RemoveRsv,                                     // "!RSV" (free virus, in cells without VRNA)
+    AddRsv(f64),                                   // "+RSV 0.01 MOI"
+    SetIfnIn { ifn: (f64, f64), region: Region },  // "=IFN 1000 U/ml in circle 50 50 10"
+    AddRsvIn { moi: (f64, f64), region: Region },  // "+RSV 1...0 MOI radial 50 50 10"
+    SaveState(PathBuf),                            // "save_state infected.json"
+    LoadState(PathBuf),                            // "load_state infected.json"
+    Load(PathBuf),                                 // "load t_0360m.csv"
+    SetRate(String, f64),                          // "set pstat_incr 0"
+    ScaleRate(String, f64),                        // "scale vrna_incr 0.5"
+    LoadParams(PathBuf),                           // "load-params pSTAT-KO.json"
+}
+
+// where protocol execution is (or is to be resumed)
+struct Position {
+    command_i: usize,
+    out_init_frame: bool, // whether initial frame in output
+    time: f64,            // end of the last run
+    run: Option<RunProgress>,
+    rates: Rates, // as changed by commands so far
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolError {
+    pub file: Option<PathBuf>, // none if protocol not read from a file
+    pub line: usize,           // 1-based
+    pub column: usize,         // 1-based
+    pub message: String,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}, ", file.display())?;
+        }
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[derive(Debug, Clone)]
 pub struct Protocol {
-    pub commands: Vec<String>,
+    pub commands: Vec<Command>,
 }
 
 impl Protocol {
-    pub fn from_text_file(protocol_file_path: &String) -> Self {
-        let mut lines = Vec::<String>::new();
-        let protocol_file_path = Path::new(protocol_file_path);
-        let file = File::open(protocol_file_path).expect("☠ 🕮 Protocol");
-        let reader = io::BufReader::new(file);
-        for line in reader.lines().flatten() {
-            lines.push(line)
+    pub fn from_text_file<P: AsRef<Path>>(protocol_file_path: P) -> Result<Self> {
+        let mut commands = Vec::<Command>::new();
+        Protocol::parse_file(protocol_file_path.as_ref(), &mut commands, &mut Vec::new())?;
+        Ok(Protocol { commands })
+    }
+
+    // Included files are searched for relative to the current working directory.
+    pub fn from_text(text: &str) -> Result<Self> {
+        let mut commands = Vec::<Command>::new();
+        Protocol::parse_text(text, None, &mut commands, &mut Vec::new())?;
+        Ok(Protocol { commands })
+    }
+
+    fn parse_file(
+        path: &Path,
+        commands: &mut Vec<Command>,
+        includers: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let text = fs::read_to_string(path)?;
+        includers.push(path.canonicalize()?);
+        Protocol::parse_text(&text, Some(path), commands, includers)?;
+        includers.pop();
+        Ok(())
+    }
+
+    // The whole protocol, including any included files, is parsed before anything is executed.
+    // Lines may end with comments starting with '#'. Blank lines are skipped.
+    fn parse_text(
+        text: &str,
+        path: Option<&Path>,
+        commands: &mut Vec<Command>,
+        includers: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let base_dir = path.and_then(Path::parent).unwrap_or(Path::new(""));
+        for (line_i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let error_at = |offset: usize, message: String| {
+                Error::Protocol(ProtocolError {
+                    file: path.map(Path::to_path_buf),
+                    line: line_i + 1,
+                    column: offset + 1,
+                    message,
+                })
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            // (keywords of file commands are separated from file names by any whitespace)
+            let keyword = line.split_whitespace().next().unwrap_or("");
+            let argument = line.trim()[keyword.len()..].trim();
+            if keyword == "save_state" && !argument.is_empty() {
+                // (saved to, and loaded from, the output directory, see Output::state_path)
+                commands.push(Command::SaveState(PathBuf::from(argument)))
+            } else if keyword == "load_state" && !argument.is_empty() {
+                commands.push(Command::LoadState(PathBuf::from(argument)))
+            } else if keyword == "load-params" && !argument.is_empty() {
+                commands.push(Command::LoadParams(base_dir.join(argument)))
+            } else if keyword == "load" && !argument.is_empty() {
+                commands.push(Command::Load(base_dir.join(argument)))
+            } else if keyword == "include" && !argument.is_empty() {
+                let (included, indent) = (argument, line.len() - line.trim_start().len());
+                let included_path = base_dir.join(included);
+                match included_path.canonicalize() {
+                    Err(err) => {
+                        return Err(error_at(indent, format!("cannot include {:?}: {}", included, err)))
+                    }
+                    Ok(canonical_path) if includers.contains(&canonical_path) => {
+                        return Err(error_at(indent, format!("cyclic include of {:?}", included)))
+                    }
+                    Ok(_) => Protocol::parse_file(&included_path, commands, includers)?,
+                }
+            } else {
+                let command = Protocol::parse_command(line, base_dir)
+                    .map_err(|(offset, message)| error_at(offset, message))?;
+                commands.push(command)
+            }
         }
-        Protocol { commands: lines }
+        Ok(())
     }
 
-    pub fn execute(
-        &self,
-        lattice: &mut Lattice,
-        rates: &Rates,
-        rng: &mut StdRng,
-        out_images: bool,
-    ) {
+    // On failure, returns byte offset in the line at which parsing failed and an error message.
+    // Amounts of =IFN and +RSV may be followed by a region (see module region), in which case
+    // they may be given as a range "start...end" of a gradient.
+    fn parse_command(line: &str, base_dir: &Path) -> std::result::Result<Command, (usize, String)> {
+        // (errors of files read while parsing are reported instead of malformed arguments)
+        let file_error = RefCell::new(None::<(usize, String)>);
         let factor = || double::<&str, (_, ErrorKind)>;
         let number = || pair::<_, _, _, (_, ErrorKind), _, _>(opt(char('-')), digit1);
 
-        let in_unit_of = |u| move |s| -> Result<f64, ParseFloatError> { Ok(u * f64::from_str(s)?) };
+        let in_unit_of = |u| move |s| -> std::result::Result<f64, ParseFloatError> { Ok(u * f64::from_str(s)?) };
         let minutes = || map_res( terminated(recognize(number()), char('m')), in_unit_of(MIN));
         let hours = || map_res( terminated(recognize(number()), char('h')), in_unit_of(HOUR));
         let days = || map_res( terminated(recognize(number()), char('d')), in_unit_of(DAY));
 
         let time = || alt((minutes(), hours(), days()));
         let timespan = || separated_pair(time(), tag("..."), time());
-        let every = || delimited(char('['), time(), char(']'));
-        let never = || tag("[]");
+        let every = || map(delimited(char('['), time(), char(']')), Some);
+        let never = || map(tag("[]"), |_| None);
+
+        let args_run = || tuple((multispace1, timespan(), multispace1, alt((every(), never()))));
+        let args_ifn = || delimited(multispace1, factor(), pair(multispace1, tag("U/ml")));
+        let args_rsv = || delimited(multispace1, factor(), pair(multispace1, tag("MOI")));
+
+        // (unlike double, decimal does not consume the dot of "1...")
+        let fraction = || opt(pair(char('.'), digit1));
+        let exponent = || opt(tuple((one_of("eE"), opt(one_of("+-")), digit1)));
+        let decimal = || {
+            map_res(recognize(tuple((number(), fraction(), exponent()))), f64::from_str)
+        };
+        let range = || separated_pair(decimal(), tag("..."), decimal());
+        let amount = || alt((range(), map(factor(), |value| (value, value))));
+        let coord = || preceded(multispace1, factor());
+        let keywords = |a, b| tuple((tag(a), multispace1, tag(b)));
+        let file_name = || take_while1(|c: char| !c.is_whitespace());
+        let circle = || tuple((coord(), coord(), coord()));
+        let corners = || tuple((coord(), coord(), coord(), coord()));
+        let region = || {
+            preceded(
+                multispace1,
+                alt((
+                    map(preceded(keywords("in", "circle"), circle()),
+                        |(x, y, r)| Region::Circle { x, y, r }),
+                    map(preceded(keywords("in", "rect"), corners()),
+                        |(x0, y0, x1, y1)| Region::Rectangle { x0, y0, x1, y1 }),
+                    map_res(preceded(keywords("in", "cells"), preceded(multispace1, file_name())),
+                        |file_name: &str| {
+                            Region::cells_from_file(base_dir.join(file_name)).map_err(|err| {
+                                let offset = file_name.as_ptr() as usize - line.as_ptr() as usize;
+                                let message = format!("cannot read cells {:?}: {}", file_name, err);
+                                file_error.replace(Some((offset, message)));
+                            })
+                        }),
+                    map(preceded(tag("linear"), corners()),
+                        |(x0, y0, x1, y1)| Region::Linear { x0, y0, x1, y1 }),
+                    map(preceded(tag("radial"), circle()),
+                        |(x, y, r)| Region::Radial { x, y, r }),
+                )),
+            )
+        };
+        // (ranges are given only for gradients)
+        let localized = |unit| {
+            verify(
+                tuple((multispace1, amount(), multispace1, tag(unit), region())),
+                |(_, (start, end), _, _, region): &(_, (f64, f64), _, _, Region)| {
+                    start == end || region.is_gradient()
+                },
+            )
+        };
+        let args_ifn_in = || map(localized("U/ml"), |(_, ifn, _, _, region)| (ifn, region));
+        let args_rsv_in = || map(localized("MOI"), |(_, moi, _, _, region)| (moi, region));
+        let layer = || map_res(alt((tag("lower"), tag("upper"), tag("both"))), Layer::from_str);
+        // (a fraction of IFNe removed is within [0, 1], a dilution factor is at least 1)
+        let fraction_out = || verify(factor(), |f: &f64| (0. ..=1.).contains(f));
+        let args_ifn_out = || {
+            pair(preceded(multispace1, fraction_out()), preceded(multispace1, layer()))
+        };
+        let args_dilution = || preceded(multispace1, verify(factor(), |&f: &f64| f >= 1.));
+        let is_name_char = |c: char| c.is_alphanumeric() || c == '_';
+        let rate_name = || map(take_while1(is_name_char), String::from);
+        let args_rate = || tuple((multispace1, rate_name(), multispace1, factor()));
 
-        let cmd_run = || tuple((tag("run"), multispace1, timespan(), multispace1, every()));
-        let cmd_run_quiet = || tuple((tag("run"), multispace1, timespan(), multispace1, never()));
-        let cmd_set_ifne = || tuple((tag("=IFN"), multispace1, factor(), multispace1, tag("U/ml")));
-        let cmd_del_ifn = || tag::<_, &str, (_, ErrorKind)>("!IFN");
-        let cmd_add_rsv = || tuple((tag("+RSV"), multispace1, factor(), multispace1, tag("MOI")));
+        let indent = line.len() - line.trim_start().len();
+        let line = line.trim();
+        let keyword = line.split_whitespace().next().unwrap_or("");
+        let args = &line[keyword.len()..];
+        let parsed: IResult<&str, Command, (&str, ErrorKind)> = match keyword {
+            "run" => map(args_run(), |(_, tspan, _, every)| Command::Run { tspan, every })(args),
+            "=IFN" => alt((
+                map(args_ifn_in(), |(ifn, region)| Command::SetIfnIn { ifn, region }),
+                map(args_ifn(), Command::SetIfn),
+            ))(args),
+            "+IFN" => map(args_ifn(), Command::AddIfn)(args),
+            "!IFN" => Ok((args, Command::RemoveIfn)),
+            "-IFN" => map(args_ifn_out(), |(f, layer)| Command::RemoveIfnFraction(f, layer))(args),
+            "/IFN" => map(args_dilution(), Command::DiluteIfn)(args),
+            "!RSV" => Ok((args, Command::RemoveRsv)),
+            "+RSV" => alt((
+                map(args_rsv_in(), |(moi, region)| Command::AddRsvIn { moi, region }),
+                map(args_rsv(), Command::AddRsv),
+            ))(args),
+            "set" => map(args_rate(), |(_, name, _, value)| Command::SetRate(name, value))(args),
+            "scale" => map(args_rate(), |(_, name, _, f)| Command::ScaleRate(name, f))(args),
+            _ => return Err((indent, format!("unknown command {:?}", keyword))),
+        };
+        let column_of = |rest: &str| indent + line.len() - rest.len();
+        match parsed {
+            Ok(("", command)) => Ok(command),
+            _ if file_error.borrow().is_some() => Err(file_error.take().unwrap()),
+            Ok((rest, _)) => {
+                let rest = rest.trim_start();
+                Err((column_of(rest), format!("unexpected {:?}", rest)))
+            }
+            Err(nom::Err::Error((rest, _)) | nom::Err::Failure((rest, _))) => {
+                Err((column_of(rest), format!("malformed arguments of {}", keyword)))
+            }
+            Err(nom::Err::Incomplete(_)) => Err((column_of(""), "incomplete command".to_string())),
+        }
+    }
+
+    pub fn execute(
+        &self,
+        lattice: &mut Lattice,
+        rates: &Rates,
+        rng: &mut Generator,
+        output: &Output,
+    ) -> Result<()> {
+        self.check_rate_changes(rates)?;
+        let time = match self.commands.iter().find(|c| matches!(c, Command::Run { .. })) {
+            Some(Command::Run { tspan, .. }) => tspan.0,
+            _ => 0.,
+        };
+        let start =
+            Position { command_i: 0, out_init_frame: false, time, run: None, rates: rates.clone() };
+        self.execute_(lattice, rng, output, start)
+    }
 
-        let mut out_init_frame = false; // whether initial frame in output
+    // Checks, before anything is executed, whether rates changed by commands exist (and are not
+    // to become negative) and whether parameter files can be loaded.
+    fn check_rate_changes(&self, rates: &Rates) -> Result<()> {
         for command in self.commands.iter() {
-            if let Ok((_, (_, _, tspan, _, dt))) = cmd_run()(&command) {
-                run_simulation(lattice, rates, rng, tspan, out_images, dt, out_init_frame);
-                out_init_frame = false;
-            } else if let Ok((_, (_, _, tspan, _, _))) = cmd_run_quiet()(&command) {
-                run_simulation_quietly(lattice, rates, rng, tspan, out_images, out_init_frame);
-                out_init_frame = false;
-            } else if let Ok((_, (_, _, ifne_uml, _, _))) = cmd_set_ifne()(&command) {
-                set_upper_ifne(lattice, ifne_uml * conversion::IFNE_U_PER_ML_TO_MOLECULE_COUNT);
-                out_init_frame = true;
-            } else if let Ok(_) = cmd_del_ifn()(&command) {
-                remove_ifne(lattice);
-                out_init_frame = true;
-            } else if let Ok((_, (_, _, moi, _, _))) = cmd_add_rsv()(&command) {
-                add_virus(lattice, rng, moi);
-                out_init_frame = true;
-            } else {
-                panic!("☠ @ command: {:?}", command);
+            match command {
+                Command::SetRate(name, value) | Command::ScaleRate(name, value) => {
+                    rates.get(name)?;
+                    if !(*value >= 0. && value.is_finite()) {
+                        let change = match command {
+                            Command::SetRate(..) => "set to",
+                            _ => "scaled by",
+                        };
+                        let message = format!("rate {:?} {} {}", name, change, value);
+                        return Err(Error::InvalidParameter(message));
+                    }
+                }
+                Command::LoadParams(path) => {
+                    let loaded = Rates::from_json_file(path)?;
+                    Rates { model: rates.model.clone(), ..loaded }.cytokine_secretors()?;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // Continues execution of this protocol from a checkpoint saved during its execution.
+    // Returns the final lattice.
+    pub fn resume(&self, checkpoint: Checkpoint, rates: &Rates, output: &Output) -> Result<Lattice> {
+        if checkpoint.command_i > self.commands.len() {
+            return Err(Error::InvalidParameter("checkpoint does not fit the protocol".into()));
+        }
+        let Checkpoint { mut lattice, mut rng, command_i, out_init_frame, time, run, .. } =
+            checkpoint;
+        // (the model is not saved in checkpoints)
+        let rates = Rates { model: rates.model.clone(), ..checkpoint.rates };
+        self.check_rate_changes(&rates)?;
+        let start = Position { command_i, out_init_frame, time, run, rates };
+        self.execute_(&mut lattice, &mut rng, output, start)?;
+        Ok(lattice)
+    }
+
+    // Rates changed by commands are in effect from the next run on (in which propensities of
+    // all events are computed anew).
+    fn execute_(
+        &self,
+        lattice: &mut Lattice,
+        rng: &mut Generator,
+        output: &Output,
+        start: Position,
+    ) -> Result<()> {
+        let Position { mut out_init_frame, mut time, mut run, mut rates, .. } = start;
+        // (bound anew after every change of rates)
+        let mut kinetics = Kinetics::bind(&rates, lattice)?;
+        for (command_i, command) in self.commands.iter().enumerate().skip(start.command_i) {
+            let checkpoint = |lattice: &Lattice, rng: &Generator, rates: &Rates, time, run| {
+                // (output files are complete up to now, as runs wait for their writers)
+                let (frames_len, summary_len) = output.file_lengths()?;
+                Ok::<_, Error>(Checkpoint {
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    command_i,
+                    out_init_frame,
+                    time,
+                    lattice: lattice.clone(),
+                    rng: rng.clone(),
+                    rates: rates.clone(),
+                    run,
+                    frames_len,
+                    summary_len,
+                })
+            };
+            let rates = &mut rates;
+            match command {
+                Command::Run { tspan, every } => {
+                    // periodic checkpoints interrupt the run at multiples of the given interval
+                    loop {
+                        let t = run.as_ref().map_or(tspan.0, |progress| progress.t);
+                        let t_stop = match output.checkpoint_interval {
+                            Some(dt) if ((t / dt).floor() + 1.) * dt < tspan.1 => {
+                                ((t / dt).floor() + 1.) * dt
+                            }
+                            _ => f64::INFINITY,
+                        };
+                        let (tspan, every) = (*tspan, *every);
+                        run = run_simulation_until(
+                            lattice, rates, &kinetics, rng, tspan, output, every, out_init_frame,
+                            run, t_stop,
+                        )?;
+                        match &run {
+                            Some(progress) => {
+                                let t = progress.t;
+                                let state = checkpoint(lattice, rng, rates, t, run.clone())?;
+                                state.save(output.file_path("checkpoint.json"))?
+                            }
+                            None => break,
+                        }
+                    }
+                    out_init_frame = false;
+                    time = tspan.1;
+                }
+                Command::SetIfn(ifne_uml) => {
+                    set_upper_ifne(lattice, ifne_uml * conversion::IFNE_U_PER_ML_TO_MOLECULE_COUNT);
+                    out_init_frame = true;
+                }
+                Command::AddIfn(ifne_uml) => {
+                    add_upper_ifne(lattice, ifne_uml * conversion::IFNE_U_PER_ML_TO_MOLECULE_COUNT);
+                    out_init_frame = true;
+                }
+                Command::RemoveIfn => {
+                    remove_ifne(lattice);
+                    out_init_frame = true;
+                }
+                Command::RemoveIfnFraction(fraction, layer) => {
+                    remove_ifne_fraction(lattice, *fraction, *layer)?;
+                    out_init_frame = true;
+                }
+                Command::DiluteIfn(factor) => {
+                    dilute_ifne(lattice, *factor)?;
+                    out_init_frame = true;
+                }
+                Command::AddRsv(moi) => {
+                    add_virus(lattice, rates, rng, *moi)?;
+                    out_init_frame = true;
+                }
+                Command::RemoveRsv => {
+                    remove_free_virus(lattice, rates)?;
+                    out_init_frame = true;
+                }
+                Command::SetIfnIn { ifn: (start, end), region } => {
+                    let to_mlcs = conversion::IFNE_U_PER_ML_TO_MOLECULE_COUNT;
+                    set_upper_ifne_in(lattice, (start * to_mlcs, end * to_mlcs), region)?;
+                    out_init_frame = true;
+                }
+                Command::AddRsvIn { moi, region } => {
+                    add_virus_in(lattice, rates, rng, *moi, region)?;
+                    out_init_frame = true;
+                }
+                Command::SaveState(file_name) => {
+                    // (resuming from this checkpoint continues with the next command)
+                    let mut state = checkpoint(lattice, rng, rates, time, None)?;
+                    state.command_i += 1;
+                    state.save(output.state_path(file_name))?
+                }
+                Command::LoadState(path) => {
+                    let path = &output.state_path(path);
+                    let state = Checkpoint::load(path)?;
+                    if state.lattice.capacity() != lattice.capacity() {
+                        return Err(Error::InvalidParameter(format!(
+                            "lattice size in state {} differs from the current one",
+                            path.display()
+                        )));
+                    }
+                    (*lattice, *rng, time) = (state.lattice, state.rng, state.time);
+                    *rates = Rates { model: rates.model.take(), ..state.rates };
+                    kinetics = Kinetics::bind(rates, lattice)?;
+                    out_init_frame = true;
+                }
+                Command::Load(path) => {
+                    // (simulation time and random generator are left as they are)
+                    lattice.load_csv(rates, path)?;
+                    out_init_frame = true;
+                }
+                Command::SetRate(name, value) => {
+                    rates.set(name, *value)?;
+                    kinetics = Kinetics::bind(rates, lattice)?;
+                }
+                Command::ScaleRate(name, factor) => {
+                    rates.set(name, factor * rates.get(name)?)?;
+                    kinetics = Kinetics::bind(rates, lattice)?;
+                }
+                Command::LoadParams(path) => {
+                    *rates = Rates { model: rates.model.take(), ..Rates::from_json_file(path)? };
+                    kinetics = Kinetics::bind(rates, lattice)?;
+                }
             }
         }
-        println!();
+        if !output.quiet {
+            println!();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_protocol_parsing() {
+    let protocol = Protocol::from_text("run -3d...-24h []\n+IFN 1000 U/ml\n!IFN\nrun 0d...1d [2h]");
+    assert_eq!(
+        protocol.unwrap().commands,
+        vec![
+            Command::Run { tspan: (-3. * DAY, -24. * HOUR), every: None },
+            Command::AddIfn(1000.),
+            Command::RemoveIfn,
+            Command::Run { tspan: (0., DAY), every: Some(2. * HOUR) },
+        ]
+    );
+    let error = |text| match Protocol::from_text(text) {
+        Err(Error::Protocol(err)) => Some((err.line, err.column)),
+        _ => None,
+    };
+    assert_eq!(error("+RSV 0.1 MOI\n*IFN 1 U/ml"), Some((2, 1)));
+    assert_eq!(error("+RSV 0.1 MOI\nrun 0d..1d [1h]"), Some((2, 7)));
+    assert_eq!(error("=IFN 10 U/ml trailing"), Some((1, 14)));
+
+    let protocol = Protocol::from_text("+RSV 1...0 MOI radial 5 5 2\n=IFN 10 U/ml in rect 0 0 4 2");
+    assert_eq!(
+        protocol.unwrap().commands,
+        vec![
+            Command::AddRsvIn { moi: (1., 0.), region: Region::Radial { x: 5., y: 5., r: 2. } },
+            Command::SetIfnIn {
+                ifn: (10., 10.),
+                region: Region::Rectangle { x0: 0., y0: 0., x1: 4., y1: 2. },
+            },
+        ]
+    );
+    assert!(Protocol::from_text("+RSV 0...1 MOI in circle 1 1 1").is_err()); // (not a gradient)
+    match Protocol::from_text("=IFN 10 U/ml in cells nonexistent.txt") {
+        Err(Error::Protocol(err)) => {
+            assert_eq!((err.line, err.column), (1, 23));
+            assert!(err.message.contains("\"nonexistent.txt\": I/O error"), "{}", err.message);
+        }
+        _ => panic!("☠ @ cells of a nonexistent file"),
     }
 }
+
+#[test]
+fn test_protocol_comments_and_includes() {
+    let protocol = Protocol::from_text("# pre-treatment\n\ninclude\tprotocols/reference.protocol\n!IFN # wash");
+    assert_eq!(
+        protocol.unwrap().commands,
+        vec![
+            Command::AddRsv(0.1),
+            Command::Run { tspan: (0., DAY), every: Some(2. * HOUR) },
+            Command::RemoveIfn,
+        ]
+    );
+    match Protocol::from_text("!IFN\n  include protocols/nonexistent.protocol") {
+        Err(Error::Protocol(err)) => assert_eq!((err.line, err.column), (2, 3)),
+        _ => panic!("☠ @ include of a nonexistent file"),
+    }
+}
+
+#[test]
+fn test_protocol_rate_changes() {
+    use crate::cell::Cell;
+    use crate::lattice::Geometry;
+    use crate::randomness::initialize_generator;
+
+    let text = "set pstat_incr 0\nscale vrna_incr 0.5\nload-params parameters/pSTAT-KO.json";
+    assert_eq!(
+        Protocol::from_text(text).unwrap().commands,
+        vec![
+            Command::SetRate("pstat_incr".to_string(), 0.),
+            Command::ScaleRate("vrna_incr".to_string(), 0.5),
+            Command::LoadParams(PathBuf::from("parameters/pSTAT-KO.json")),
+        ]
+    );
+    assert_eq!(
+        Protocol::from_text("load-params\t parameters/pSTAT-KO.json").unwrap().commands,
+        vec![Command::LoadParams(PathBuf::from("parameters/pSTAT-KO.json"))]
+    );
+    assert!(Protocol::from_text("set pstat_incr").is_err());
+
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let geometry = Geometry { width: 30, height: 30, occupancy: 1.0, ..Geometry::default() };
+    let mut rng = initialize_generator(0x5eed);
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    let unknown = Protocol::from_text("+RSV 0.1 MOI\nset vrna_inc 0").unwrap();
+    assert!(unknown.execute(&mut lattice, &rates, &mut rng, &Output::default()).is_err());
+    assert!(lattice.cells.iter().all(|c| c.molecules == Cell::MIN.molecules)); // (checked first)
+    for text in ["+RSV 0.1 MOI\nset vrna_incr -1", "+RSV 0.1 MOI\nscale vrna_incr -0.5"] {
+        let negative = Protocol::from_text(text).unwrap();
+        assert!(negative.execute(&mut lattice, &rates, &mut rng, &Output::default()).is_err());
+        assert!(lattice.cells.iter().all(|c| c.molecules == Cell::MIN.molecules));
+    }
+
+    // (a change of rates that the model does not admit fails the protocol, not the run)
+    let model = crate::model::Model::from_json_file("models/default.json", &rates).unwrap();
+    let modeled = Rates { model: Some(std::sync::Arc::new(model)), ..rates.clone() };
+    let negative = Protocol::from_text("run 0m...1h []\nset vrna_incr -1\nrun 1h...2h []").unwrap();
+    assert!(negative.execute(&mut lattice, &modeled, &mut rng, &Output::default()).is_err());
+
+    // a protocol with a change of rates is equivalent to protocols executed one after another
+    let execute = |texts: &[&str], rates: &[&Rates], output: &Output| {
+        let mut rng = initialize_generator(0x5eed);
+        let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+        for (text, rates) in texts.iter().zip(rates) {
+            let protocol = Protocol::from_text(text).unwrap();
+            protocol.execute(&mut lattice, rates, &mut rng, output).unwrap();
+        }
+        let mut csv = Vec::<u8>::new();
+        lattice.write_csv(rates[0], &mut csv).unwrap();
+        csv
+    };
+    let mut halved = rates.clone();
+    halved.vrna_incr *= 0.5;
+    let texts = ["+RSV 0.3 MOI\nrun 0m...2h []", "run 2h...4h []"];
+    let reference = execute(&texts, &[&rates, &halved], &Output::default());
+    let text = "+RSV 0.3 MOI\nrun 0m...2h []\nscale vrna_incr 0.5\nrun 2h...4h []";
+    assert_eq!(execute(&[text], &[&rates], &Output::default()), reference);
+
+    // changed rates are saved in checkpoints
+    let dir = std::env::temp_dir().join(format!("visavis-rate-changes-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = Output { dir: dir.clone(), checkpoint_interval: Some(200.), ..Output::default() };
+    execute(&[text], &[&rates], &output);
+    let checkpoint = Checkpoint::load(dir.join("checkpoint.json")).unwrap();
+    assert_eq!(checkpoint.rates.vrna_incr, halved.vrna_incr);
+    let resumed = Protocol::from_text(text).unwrap().resume(checkpoint, &rates, &Output::default());
+    let mut csv = Vec::<u8>::new();
+    resumed.unwrap().write_csv(&rates, &mut csv).unwrap();
+    assert_eq!(csv, reference);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_protocol_medium_exchange() {
+    use crate::lattice::Geometry;
+    use crate::molecule::Mol;
+    use crate::randomness::initialize_generator;
+
+    let text = "=IFN 100 U/ml\n-IFN 0.25 upper\n/IFN 2\n+RSV 1 MOI\n!RSV";
+    let protocol = Protocol::from_text(text).unwrap();
+    assert_eq!(
+        protocol.commands[1..3],
+        [Command::RemoveIfnFraction(0.25, Layer::Upper), Command::DiluteIfn(2.)]
+    );
+    assert!(Protocol::from_text("-IFN 0.5").is_err());
+    assert!(Protocol::from_text("-IFN 0.5 middle").is_err());
+
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let geometry = Geometry { width: 20, height: 20, occupancy: 1.0, ..Geometry::default() };
+    let mut rng = initialize_generator(0x5eed);
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    lattice.cells[0].molecules[Mol::Vrna as usize] = 1; // (as if replicating already)
+    protocol.execute(&mut lattice, &rates, &mut rng, &Output::default()).unwrap();
+    let upper = 100. * conversion::IFNE_U_PER_ML_TO_MOLECULE_COUNT * 0.75 / 2.;
+    assert!(lattice.cytokines.iter().all(|&c| c == [0., upper]));
+    let infected = lattice.cells.iter().filter(|c| c.molecules[Mol::Vinf as usize] > 0);
+    assert_eq!(infected.count(), 1);
+
+    // (fractions and dilution factors out of range are rejected before execution)
+    let error = |text| match Protocol::from_text(text) {
+        Err(Error::Protocol(err)) => Some((err.line, err.column)),
+        _ => None,
+    };
+    assert_eq!(error("-IFN 1.5 both"), Some((1, 6)));
+    assert_eq!(error("!IFN\n-IFN -0.1 upper"), Some((2, 6)));
+    assert_eq!(error("/IFN 0.5"), Some((1, 6)));
+    assert!(Protocol::from_text("-IFN 1 both\n-IFN 0 lower\n/IFN 1").is_ok());
+}
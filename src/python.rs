@@ -0,0 +1,269 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Python extension module (built when cargo feature "python" is enabled).
+
+use crate::checkpoint::Checkpoint;
+use crate::commands::{add_upper_ifne, add_virus, remove_ifne, run_simulation_quietly, set_upper_ifne};
+use crate::commands::{add_virus_in, set_upper_ifne_in};
+use crate::commands::{dilute_ifne, remove_free_virus, remove_ifne_fraction, Layer};
+use crate::error::Error;
+use crate::lattice::{Geometry, Lattice};
+use crate::model::species_names;
+use crate::molecule::MOLECULE_NAMES;
+use crate::output::Output;
+use crate::protocol::{Command, Protocol};
+use crate::randomness::{gen_seed_from_time, initialize_generator, Generator};
+use crate::rates::Rates;
+use crate::units::conversion;
+
+use numpy::ndarray::Array2;
+use numpy::{IntoPyArray, PyArray1};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+impl From<Error> for PyErr {
+    fn from(err: Error) -> PyErr {
+        match err {
+            Error::Io(_) => PyIOError::new_err(err.to_string()),
+            _ => PyValueError::new_err(err.to_string()),
+        }
+    }
+}
+
+#[pyclass(name = "Simulation")]
+pub struct PySimulation {
+    lattice: Lattice,
+    rates: Rates,
+    rng: Generator,
+    #[pyo3(get)]
+    time: f64, // in minutes
+    #[pyo3(get)]
+    seed: u64,
+}
+
+#[pymethods]
+impl PySimulation {
+    // Parameters are given as a dict, with the same entries as the parameters JSON file.
+    #[new]
+    #[pyo3(signature = (parameters, seed=None, width=None, height=None, occupancy=None))]
+    fn new(
+        py: Python,
+        parameters: &Bound<PyDict>,
+        seed: Option<u64>,
+        width: Option<usize>,
+        height: Option<usize>,
+        occupancy: Option<f64>,
+    ) -> PyResult<Self> {
+        let json: String = py.import("json")?.call_method1("dumps", (parameters,))?.extract()?;
+        let rates: Rates = serde_json::from_str(&json).map_err(Error::from)?;
+        rates.cytokine_secretors()?;
+        let mut geometry: Geometry = serde_json::from_str(&json).map_err(Error::from)?;
+        geometry.width = width.unwrap_or(geometry.width);
+        geometry.height = height.unwrap_or(geometry.height);
+        geometry.occupancy = occupancy.unwrap_or(geometry.occupancy);
+        let seed = seed.unwrap_or_else(gen_seed_from_time);
+        let mut rng = initialize_generator(seed);
+        let lattice = Lattice::new(&geometry, &mut rng)?;
+        rates.check_transport_stability(lattice.neighborhoods.max_exchange_weight())?;
+        Ok(PySimulation { lattice, rates, rng, time: 0., seed })
+    }
+
+    fn add_virus(&mut self, moi: f64) -> PyResult<()> {
+        Ok(add_virus(&mut self.lattice, &self.rates, &mut self.rng, moi)?)
+    }
+
+    fn set_upper_ifn(&mut self, ifne_uml: f64) {
+        set_upper_ifne(&mut self.lattice, ifne_uml * conversion::IFNE_U_PER_ML_TO_MOLECULE_COUNT)
+    }
+
+    fn add_upper_ifn(&mut self, ifne_uml: f64) {
+        add_upper_ifne(&mut self.lattice, ifne_uml * conversion::IFNE_U_PER_ML_TO_MOLECULE_COUNT)
+    }
+
+    fn remove_ifn(&mut self) {
+        remove_ifne(&mut self.lattice)
+    }
+
+    // Removes a fraction of IFNe from the "upper", "lower", or "both" medium subcompartments.
+    #[pyo3(signature = (fraction, layer="both"))]
+    fn remove_ifn_fraction(&mut self, fraction: f64, layer: &str) -> PyResult<()> {
+        Ok(remove_ifne_fraction(&mut self.lattice, fraction, Layer::from_str(layer)?)?)
+    }
+
+    fn dilute_ifn(&mut self, factor: f64) -> PyResult<()> {
+        Ok(dilute_ifne(&mut self.lattice, factor)?)
+    }
+
+    fn remove_free_virus(&mut self) -> PyResult<()> {
+        Ok(remove_free_virus(&mut self.lattice, &self.rates)?)
+    }
+
+    // Saves the state (lattice, time, and random generator) to a file, see module checkpoint.
+    fn save_state(&self, path: PathBuf) -> PyResult<()> {
+        let checkpoint = Checkpoint {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            command_i: 0,
+            out_init_frame: false,
+            time: self.time,
+            lattice: self.lattice.clone(),
+            rng: self.rng.clone(),
+            rates: self.rates.clone(),
+            run: None,
+            frames_len: None,
+            summary_len: None,
+        };
+        Ok(checkpoint.save(path)?)
+    }
+
+    fn load_state(&mut self, path: PathBuf) -> PyResult<()> {
+        let checkpoint = Checkpoint::load(path)?;
+        (self.lattice, self.rng, self.time) = (checkpoint.lattice, checkpoint.rng, checkpoint.time);
+        self.rates = Rates { model: self.rates.model.take(), ..checkpoint.rates };
+        Ok(())
+    }
+
+    // Replaces the state of cells and IFNe with the one from a CSV file written by a "run".
+    fn load(&mut self, path: PathBuf) -> PyResult<()> {
+        Ok(self.lattice.load_csv(&self.rates, path)?)
+    }
+
+    // Rates changed between runs are in effect from the next run on.
+    fn set_rate(&mut self, name: &str, value: f64) -> PyResult<()> {
+        Ok(self.rates.set(name, value)?)
+    }
+
+    fn scale_rate(&mut self, name: &str, factor: f64) -> PyResult<()> {
+        Ok(self.rates.set(name, factor * self.rates.get(name)?)?)
+    }
+
+    fn load_params(&mut self, path: PathBuf) -> PyResult<()> {
+        let rates = Rates { model: self.rates.model.clone(), ..Rates::from_json_file(path)? };
+        rates.cytokine_secretors()?;
+        self.rates = rates;
+        Ok(())
+    }
+
+    // Current state as a dict of NumPy arrays: "alive" (cells), "molecules" (cells × species),
+    // "ifne" (cells × 2, with lower and upper subcompartment in columns), other cytokines (given
+    // in parameters) by name (likewise), "virions" (cells, free virions in the medium, zero
+    // unless simulated), and factors of rate constants varying among cells, as "<name>_factor"
+    // (cells, see module variability).
+    fn snapshot<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let n_cells = self.lattice.capacity();
+        let alive = self.lattice.cells.iter().map(|c| c.alive).collect::<Vec<_>>();
+        let n_species = species_names(self.rates.model.as_deref()).len();
+        let molecules = self.lattice.cells.iter().flat_map(|c| c.molecules[..n_species].to_vec());
+        let molecules = molecules.collect::<Vec<_>>();
+        let ifne = self.lattice.cytokines.iter().flatten().copied().collect::<Vec<_>>();
+        let snapshot = PyDict::new(py);
+        snapshot.set_item("time", self.time)?;
+        snapshot.set_item("alive", PyArray1::from_vec(py, alive))?;
+        let molecules = Array2::from_shape_vec((n_cells, n_species), molecules).unwrap();
+        snapshot.set_item("molecules", molecules.into_pyarray(py))?;
+        let ifne = Array2::from_shape_vec((n_cells, 2), ifne).unwrap();
+        snapshot.set_item("ifne", ifne.into_pyarray(py))?;
+        for (ci, cytokine) in self.rates.cytokines.iter().enumerate() {
+            let amounts = (0..n_cells).flat_map(|i| self.lattice.other_cytokine(ci, i));
+            let amounts = Array2::from_shape_vec((n_cells, 2), amounts.collect()).unwrap();
+            snapshot.set_item(cytokine.name.as_str(), amounts.into_pyarray(py))?;
+        }
+        snapshot.set_item("virions", PyArray1::from_vec(py, self.lattice.virions.clone()))?;
+        for rate_factors in self.lattice.rate_factors.iter() {
+            let factors = PyArray1::from_vec(py, rate_factors.factors.clone());
+            snapshot.set_item(format!("{}_factor", rate_factors.name), factors)?;
+        }
+        Ok(snapshot)
+    }
+
+    // Simulates until the given time (in minutes), returning snapshots taken at the start
+    // and then every `every` minutes (none if `every` is not given).
+    #[pyo3(signature = (until, every=None))]
+    fn run<'py>(
+        &mut self,
+        py: Python<'py>,
+        until: f64,
+        every: Option<f64>,
+    ) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let mut snapshots = Vec::new();
+        self.run_(py, (self.time, until), every, &mut snapshots)?;
+        Ok(snapshots)
+    }
+
+    // Executes a protocol given as text (in the format of protocol files), returning snapshots
+    // requested by its "run" commands.
+    fn execute<'py>(&mut self, py: Python<'py>, protocol: &str) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let protocol = Protocol::from_text(protocol)?;
+        let mut snapshots = Vec::new();
+        for command in protocol.commands.iter() {
+            match command {
+                Command::Run { tspan, every } => self.run_(py, *tspan, *every, &mut snapshots)?,
+                Command::SetIfn(ifne_uml) => self.set_upper_ifn(*ifne_uml),
+                Command::AddIfn(ifne_uml) => self.add_upper_ifn(*ifne_uml),
+                Command::RemoveIfn => self.remove_ifn(),
+                Command::RemoveIfnFraction(fraction, layer) => {
+                    remove_ifne_fraction(&mut self.lattice, *fraction, *layer)?
+                }
+                Command::DiluteIfn(factor) => self.dilute_ifn(*factor)?,
+                Command::AddRsv(moi) => self.add_virus(*moi)?,
+                Command::RemoveRsv => self.remove_free_virus()?,
+                Command::SetIfnIn { ifn: (start, end), region } => {
+                    let to_mlcs = conversion::IFNE_U_PER_ML_TO_MOLECULE_COUNT;
+                    set_upper_ifne_in(&mut self.lattice, (start * to_mlcs, end * to_mlcs), region)?
+                }
+                Command::AddRsvIn { moi, region } => {
+                    add_virus_in(&mut self.lattice, &self.rates, &mut self.rng, *moi, region)?
+                }
+                Command::SaveState(path) => self.save_state(path.clone())?,
+                Command::LoadState(path) => self.load_state(path.clone())?,
+                Command::Load(path) => self.load(path.clone())?,
+                Command::SetRate(name, value) => self.set_rate(name, *value)?,
+                Command::ScaleRate(name, factor) => self.scale_rate(name, *factor)?,
+                Command::LoadParams(path) => self.load_params(path.clone())?,
+            }
+        }
+        Ok(snapshots)
+    }
+}
+
+impl PySimulation {
+    fn run_<'py>(
+        &mut self,
+        py: Python<'py>,
+        tspan: (f64, f64),
+        every: Option<f64>,
+        snapshots: &mut Vec<Bound<'py, PyDict>>,
+    ) -> PyResult<()> {
+        if every.is_some_and(|dt| dt <= 0.) {
+            return Err(Error::InvalidParameter("snapshot interval must be positive".into()).into());
+        }
+        self.time = tspan.0;
+        loop {
+            if every.is_some() {
+                snapshots.push(self.snapshot(py)?);
+            }
+            if self.time >= tspan.1 {
+                break;
+            }
+            let t_next = every.map_or(tspan.1, |dt| (self.time + dt).min(tspan.1));
+            let (lattice, rates, rng) = (&mut self.lattice, &self.rates, &mut self.rng);
+            let tspan = (self.time, t_next);
+            let output = Output::default(); // (no files are written in quiet runs)
+            py.detach(|| run_simulation_quietly(lattice, rates, rng, tspan, &output, false))?;
+            self.time = t_next;
+        }
+        Ok(())
+    }
+}
+
+#[pymodule]
+fn vis_a_vis(module: &Bound<PyModule>) -> PyResult<()> {
+    module.add_class::<PySimulation>()?;
+    module.add("SPECIES", MOLECULE_NAMES)?;
+    Ok(())
+}
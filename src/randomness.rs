@@ -3,24 +3,51 @@
 // Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
 // Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
 
-use rand::{rngs::StdRng, SeedableRng};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 
-fn gen_seed_from_time() -> [u8; 32] {
+use std::num::ParseIntError;
+
+// the algorithm of rand's StdRng, which, unlike StdRng, can be serialized into checkpoints
+pub type Generator = ChaCha12Rng;
+
+pub fn gen_seed_from_time() -> u64 {
     let now = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap();
-    macro_rules! wrap {
-        ($a: expr) => {
-            (($a as u128) % (u8::max_value() as u128)) as u8
-        };
+    (now.as_nanos() % (u64::MAX as u128)) as u64
+}
+
+// accepts both decimal (e.g. "12345") and hexadecimal (e.g. "0x3039") notation
+pub fn parse_seed(seed_s: &str) -> Result<u64, ParseIntError> {
+    match seed_s.strip_prefix("0x").or_else(|| seed_s.strip_prefix("0X")) {
+        Some(hex_s) => u64::from_str_radix(hex_s, 16),
+        None => seed_s.parse::<u64>(),
     }
-    let mut s = [0u8; 32];
-    s[0] = wrap!(now.as_millis());
-    s[15] = wrap!(now.as_secs());
-    s[31] = wrap!(now.as_nanos());
-    s
 }
 
-pub fn initialize_generator() -> StdRng {
-    SeedableRng::from_seed(gen_seed_from_time())
+pub fn initialize_generator(seed: u64) -> Generator {
+    Generator::seed_from_u64(seed)
+}
+
+// Seeds of independent replicates of a run (the i-th seed does not depend on their number).
+pub fn derive_seeds(seed: u64, n: usize) -> Vec<u64> {
+    let mut rng = initialize_generator(seed);
+    (0..n).map(|_| rng.next_u64()).collect()
+}
+
+#[test]
+fn test_seed_parsing() {
+    assert_eq!(parse_seed("12345"), Ok(12345));
+    assert_eq!(parse_seed("0x3039"), Ok(12345));
+    assert_eq!(parse_seed("0XFFFFFFFFFFFFFFFF"), Ok(u64::MAX));
+    assert!(parse_seed("seed").is_err());
+}
+
+#[test]
+fn test_derived_seeds() {
+    let seeds = derive_seeds(0x5eed, 8);
+    assert_eq!(seeds[..4], derive_seeds(0x5eed, 4));
+    assert!((1..seeds.len()).all(|i| !seeds[..i].contains(&seeds[i])));
+    assert!(!seeds.contains(&0x5eed));
 }
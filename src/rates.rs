@@ -3,15 +3,16 @@
 // Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
 // Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
 
-use crate::units::MIN;
+use crate::error::{Error, Result};
+use crate::model::{species_names, Model};
 
 use std::fs;
+use std::path::Path;
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 use serde_json::from_str;
 
-pub const TIMESTEP: f64 = 0.1 * MIN;
-
 // chemical reaction rates (stochastic)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Rates {
@@ -40,23 +41,247 @@ pub struct Rates {
     pub isg_inh_vrna: f64,
     pub isg_inh_vprot: f64,
     pub isg_pro_pirf3: f64,
+    // deterministic transport in the medium (see module transport and
+    // Rates::check_transport_stability)
+    #[serde(default)]
+    pub transport_scheme: TransportScheme,
+    #[serde(default = "transport::default_timestep")]
+    pub timestep: f64, // of the transport scheme
+    #[serde(default = "transport::default_k_ifne_ll")]
+    pub k_ifne_ll: f64, // exchange between neighbors in the lower subcompartment (per neighbor)
+    #[serde(default = "transport::default_k_ifne_uu")]
+    pub k_ifne_uu: f64, // exchange between neighbors in the upper subcompartment (per neighbor)
+    #[serde(default = "transport::default_k_ifne_lu")]
+    pub k_ifne_lu: f64, // transport from the lower to the upper subcompartment
+    #[serde(default = "transport::default_k_ifne_ul")]
+    pub k_ifne_ul: f64, // transport from the upper to the lower subcompartment
+    // free virions in the medium (optional, see Rates::free_virions)
+    #[serde(default)]
+    pub k_vir_sec: f64, // release by cells with active Vprot
+    #[serde(default)]
+    pub q_vire: f64, // decay
+    #[serde(default)]
+    pub k_vire_uptake: f64, // uptake by alive cells, each virion taken up infects the cell
+    #[serde(default)]
+    pub k_vire_exch: f64, // exchange between neighboring nodes (per neighbor)
+    #[serde(default)]
+    pub cytokines: Vec<Cytokine>, // extracellular signaling species other than IFNe
+    #[serde(skip)]
+    pub model: Option<Arc<Model>>, // if set, replaces the built-in wiring of interactions
 }
 
 impl Rates {
-    pub fn from_json_file(params_filename: &String) -> Self {
-        let contents = fs::read_to_string(params_filename).expect("☠ 🕮 JSON");
-        from_str(&contents).unwrap()
+    pub fn from_json_file<P: AsRef<Path>>(params_filename: P) -> Result<Self> {
+        let contents = fs::read_to_string(params_filename)?;
+        let rates: Rates = from_str(&contents)?;
+        rates.check_transport_constants()?;
+        Ok(rates)
+    }
+
+    // Free virions are simulated (and output) only if they are released by infected cells, so
+    // that otherwise virus spreads only from cell to cell.
+    pub fn free_virions(&self) -> bool {
+        self.k_vir_sec > 0.
+    }
+
+    // constants of transport in the medium by name of the medium, as (decay, lower -> upper,
+    // upper -> lower, lower exchange, upper exchange)
+    fn transport_constants(&self) -> Vec<(&str, [f64; 5])> {
+        let mut media = vec![(
+            "IFNe",
+            [self.q_ifne, self.k_ifne_lu, self.k_ifne_ul, self.k_ifne_ll, self.k_ifne_uu],
+        )];
+        for c in self.cytokines.iter() {
+            media.push((c.name.as_str(), [c.q, c.k_lu, c.k_ul, c.k_ll, c.k_uu]))
+        }
+        if self.free_virions() {
+            // (virions are in a single subcompartment, they also leave it by uptake)
+            let q = self.q_vire + self.k_vire_uptake;
+            media.push(("VIRe", [q, 0., 0., self.k_vire_exch, 0.]))
+        }
+        media
+    }
+
+    // (the time step and transport constants, whatever the lattice)
+    pub fn check_transport_constants(&self) -> Result<()> {
+        if !(self.timestep > 0. && self.timestep.is_finite()) {
+            return Err(Error::InvalidParameter(format!("time step {}", self.timestep)));
+        }
+        for (name, constants) in self.transport_constants() {
+            if constants.iter().any(|&k| !(k >= 0. && k.is_finite())) {
+                let message = format!("negative or non-finite transport constants of {}", name);
+                return Err(Error::InvalidParameter(message));
+            }
+        }
+        Ok(())
+    }
+
+    // Transport of IFNe, of other cytokines and of free virions is computed by default with an
+    // explicit scheme, in which the amount in a lattice node after a time step is a linear
+    // combination of amounts before the step. The scheme is stable only if all these coefficients
+    // are non-negative, i.e., if no more than the amount in a node may leave it in a time step.
+    // (The implicit scheme is stable for any time step.) Exchange between nodes is bounded by the
+    // largest total weight of contacts of a node, so stability is checked only once the lattice
+    // is known (see Kinetics::bind).
+    pub fn check_transport_stability(&self, max_exchange_weight: f64) -> Result<()> {
+        self.check_transport_constants()?;
+        for (name, [q, lu, ul, ll, uu]) in self.transport_constants() {
+            let outflow =
+                f64::max(q + lu + max_exchange_weight * ll, q + ul + max_exchange_weight * uu);
+            if self.transport_scheme == TransportScheme::Explicit && outflow * self.timestep > 1. {
+                return Err(Error::InvalidParameter(format!(
+                    "time step {} too long for stable transport of {} (should be at most {})",
+                    self.timestep,
+                    name,
+                    1. / outflow
+                )));
+            }
+        }
+        Ok(())
     }
+
+    // Slots of species (of the model, if given) whose activity makes cells secrete each of the
+    // cytokines other than IFNe.
+    pub fn cytokine_secretors(&self) -> Result<Vec<usize>> {
+        let species_names = species_names(self.model.as_deref());
+        let mut secretors = Vec::with_capacity(self.cytokines.len());
+        for (ci, c) in self.cytokines.iter().enumerate() {
+            let invalid = |message: &str| {
+                Err(Error::InvalidParameter(format!("cytokine {:?}: {}", c.name, message)))
+            };
+            let name = c.name.as_str();
+            let taken = ["IFNe", "VIRe"].contains(&name)
+                || species_names.contains(&name)
+                || self.cytokines[..ci].iter().any(|other| other.name == name);
+            if taken || name.is_empty() || name.contains(',') {
+                return invalid("name repeated or invalid");
+            }
+            let constants = [c.k_sec, c.q, c.k_lu, c.k_ul, c.k_ll, c.k_uu, c.pstat, c.mm_pstat];
+            if constants.iter().any(|&k| !(k >= 0. && k.is_finite())) {
+                return invalid("negative or non-finite constants");
+            }
+            match species_names.iter().position(|&name| name == c.secreted_by) {
+                Some(mi) => secretors.push(mi),
+                None => return invalid(&format!("unknown species {:?}", c.secreted_by)),
+            }
+        }
+        Ok(secretors)
+    }
+
+    // Rates are accessed by name as in parameter files.
+    pub fn get(&self, name: &str) -> Result<f64> {
+        match serde_json::to_value(self)?.get(name).and_then(serde_json::Value::as_f64) {
+            Some(value) => Ok(value),
+            None => Err(Error::InvalidParameter(format!("unknown rate {:?}", name))),
+        }
+    }
+
+    // (all rates, and other parameters accessed by name, are non-negative)
+    pub fn set(&mut self, name: &str, value: f64) -> Result<()> {
+        let mut rates = serde_json::to_value(&*self)?;
+        match rates.get_mut(name) {
+            Some(rate) if rate.is_number() && value >= 0. && value.is_finite() => {
+                *rate = value.into()
+            }
+            Some(_) => return Err(Error::InvalidParameter(format!("{} = {}", name, value))),
+            None => return Err(Error::InvalidParameter(format!("unknown rate {:?}", name))),
+        }
+        let changed = Rates { model: self.model.clone(), ..serde_json::from_value(rates)? };
+        changed.check_transport_constants()?;
+        *self = changed;
+        Ok(())
+    }
+}
+
+// Numerical scheme of transport in the medium (see Simulation::cytokine_transport_step): explicit
+// (forward Euler), or implicit (TR-BDF2), which allows for much longer time steps.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportScheme {
+    #[default]
+    Explicit,
+    Implicit,
 }
 
-// transport of extracelluar interferon-beta (deterministic)
+// Extracellular signaling species, such as IFN-λ, given in parameter files as, e.g.,
+//   "cytokines": [{"name": "IFNl", "secreted_by": "IFNi", "k_sec": 4000, "q": 0.0007,
+//                  "k_lu": 0.5, "k_ul": 0.005, "k_ll": 0.04, "k_uu": 0.04,
+//                  "pstat": 0.5, "mm_pstat": 500}]
+// Cytokines are transported (deterministically) as IFNe, with constants given per minute. In
+// the built-in wiring, the cytokine in the lower subcompartment adds to the activation of STAT
+// by IFNe a term pstat x/(mm_pstat + x); models refer to cytokines by name (see module model).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Cytokine {
+    pub name: String,
+    pub secreted_by: String, // species which, when active, makes the cell secrete the cytokine
+    pub k_sec: f64,          // secretion
+    pub q: f64,              // decay
+    pub k_lu: f64,           // transport from the lower to the upper subcompartment
+    pub k_ul: f64,           // transport from the upper to the lower subcompartment
+    pub k_ll: f64,           // exchange between neighbors in the lower subcompartment
+    pub k_uu: f64,           // exchange between neighbors in the upper subcompartment
+    #[serde(default)]
+    pub pstat: f64,
+    #[serde(default)]
+    pub mm_pstat: f64,
+}
+
+// transport of extracelluar interferon-beta (deterministic), defaults of parameters
 pub mod transport {
     use crate::lattice::Lattice;
-    use crate::rates::TIMESTEP;
     use crate::units::MIN;
 
-    pub const K_IFNE_LL_DT: f64 = 0.5 / (Lattice::N_NEIGHBORS as f64) / MIN * TIMESTEP;
-    pub const K_IFNE_UU_DT: f64 = K_IFNE_LL_DT;
-    pub const K_IFNE_LU_DT: f64 = 5. * 0.1 / MIN * TIMESTEP;
-    pub const K_IFNE_UL_DT: f64 = 5. * 0.001 / MIN * TIMESTEP;
+    pub const TIMESTEP: f64 = 0.1 * MIN;
+    pub const K_IFNE_LL: f64 = 0.5 / (Lattice::N_NEIGHBORS as f64) / MIN;
+    pub const K_IFNE_UU: f64 = K_IFNE_LL;
+    pub const K_IFNE_LU: f64 = 5. * 0.1 / MIN;
+    pub const K_IFNE_UL: f64 = 5. * 0.001 / MIN;
+
+    pub fn default_timestep() -> f64 {
+        TIMESTEP
+    }
+
+    pub fn default_k_ifne_ll() -> f64 {
+        K_IFNE_LL
+    }
+
+    pub fn default_k_ifne_uu() -> f64 {
+        K_IFNE_UU
+    }
+
+    pub fn default_k_ifne_lu() -> f64 {
+        K_IFNE_LU
+    }
+
+    pub fn default_k_ifne_ul() -> f64 {
+        K_IFNE_UL
+    }
+}
+
+#[test]
+fn test_transport_parameters() {
+    use crate::lattice::Lattice;
+
+    let mut rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    assert_eq!(rates.timestep, transport::TIMESTEP);
+    assert_eq!(rates.k_ifne_ll * rates.timestep, 0.5 / 6. * 0.1);
+    assert_eq!(rates.get("k_ifne_lu").unwrap(), transport::K_IFNE_LU);
+
+    // a longer time step is stable only with slower transport, or on a lattice of fewer contacts
+    let (hexagonal, square) = (Lattice::N_NEIGHBORS as f64, 4.);
+    rates.set("timestep", 1.1).unwrap();
+    assert!(rates.check_transport_stability(hexagonal).is_err());
+    rates.check_transport_stability(square).unwrap();
+    rates.set("timestep", 2.).unwrap();
+    assert!(rates.check_transport_stability(square).is_err());
+    rates.set("k_ifne_ll", 0.01).unwrap();
+    rates.set("k_ifne_uu", 0.01).unwrap();
+    rates.set("k_ifne_lu", 0.1).unwrap();
+    rates.check_transport_stability(hexagonal).unwrap();
+    assert!(rates.set("k_ifne_ul", -0.1).is_err());
+    assert!(rates.set("vrna_incr", -1.).is_err());
+    assert!(rates.set("vrna_incr", f64::INFINITY).is_err());
+    assert!(rates.set("timestep", 0.).is_err());
+    assert_eq!(rates.timestep, 2.);
 }
@@ -0,0 +1,164 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Regions of the lattice to which protocol commands may be restricted, and gradients of amounts
+// applied by these commands. Node (x, y) is the lattice node of index x + y * width; regions
+// are given in coordinates of nodes (see Lattice::coordinates). Distances are Euclidean between
+// centers of nodes (with unit spacing of neighbors), which, in the hexagonal lattice, are
+// located at (x + y/2, y √3/2) (see Topology::position). Circles and radial gradients wrap
+// around the lattice with the periodic boundary.
+
+use crate::error::{Error, Result};
+use crate::lattice::{Boundary, Lattice};
+use crate::topology::Topology;
+
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Region {
+    Circle { x: f64, y: f64, r: f64 },
+    Rectangle { x0: f64, y0: f64, x1: f64, y1: f64 }, // nodes with x0 <= x <= x1, y0 <= y <= y1
+    Cells(Vec<usize>),                                 // node indices
+    Linear { x0: f64, y0: f64, x1: f64, y1: f64 },    // gradient from (x0, y0) to (x1, y1)
+    Radial { x: f64, y: f64, r: f64 },                 // gradient from (x, y) to distance r
+}
+
+impl Region {
+    // Reads node indices separated by whitespace or commas.
+    pub fn cells_from_file<P: AsRef<Path>>(path: P) -> Result<Region> {
+        let contents = fs::read_to_string(path.as_ref())?;
+        let ids = contents
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|id| !id.is_empty())
+            .map(|id| id.parse::<usize>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|err| {
+                Error::InvalidParameter(format!("{}: {}", path.as_ref().display(), err))
+            })?;
+        Ok(Region::Cells(ids))
+    }
+
+    pub fn is_gradient(&self) -> bool {
+        matches!(self, Region::Linear { .. } | Region::Radial { .. })
+    }
+
+    // Nodes of the region, each with its relative position along the gradient (from 0 at the
+    // start to 1 at the end, and beyond; 0 in regions without a gradient). Gradients cover the
+    // whole lattice.
+    pub fn grades(&self, lattice: &Lattice) -> Result<Vec<(usize, f64)>> {
+        let (width, height) = (lattice.width, lattice.height);
+        let (periodic, topology) = (lattice.boundary == Boundary::Periodic, lattice.topology);
+        let node = |i: usize| lattice.coordinates(i);
+        let distance = |a, b| distance(a, b, width, height, periodic, topology);
+        let position = |node| topology.position(node);
+        let all_nodes = 0..lattice.capacity();
+        Ok(match *self {
+            Region::Circle { x, y, r } => all_nodes
+                .filter(|&i| distance(node(i), (x, y)) <= r)
+                .map(|i| (i, 0.))
+                .collect(),
+            Region::Rectangle { x0, y0, x1, y1 } => all_nodes
+                .filter(|&i| {
+                    let (x, y) = node(i);
+                    (x0..=x1).contains(&x) && (y0..=y1).contains(&y)
+                })
+                .map(|i| (i, 0.))
+                .collect(),
+            Region::Cells(ref ids) => {
+                if let Some(id) = ids.iter().find(|&&id| id >= lattice.capacity()) {
+                    return Err(Error::InvalidParameter(format!("cell id {} out of lattice", id)));
+                }
+                ids.iter().map(|&i| (i, 0.)).collect()
+            }
+            Region::Linear { x0, y0, x1, y1 } => {
+                let ((ax, ay), (bx, by)) = (position((x0, y0)), position((x1, y1)));
+                let length2 = (bx - ax).powi(2) + (by - ay).powi(2);
+                let grade = |i| {
+                    let (px, py) = position(node(i));
+                    let projection = (px - ax) * (bx - ax) + (py - ay) * (by - ay);
+                    if length2 > 0. { (projection / length2).clamp(0., 1.) } else { 0. }
+                };
+                all_nodes.map(|i| (i, grade(i))).collect()
+            }
+            Region::Radial { x, y, r } => {
+                let grade = |i| match distance(node(i), (x, y)) {
+                    d if r > 0. => (d / r).min(1.),
+                    d => if d > 0. { 1. } else { 0. },
+                };
+                all_nodes.map(|i| (i, grade(i))).collect()
+            }
+        })
+    }
+}
+
+// between nodes, the shortest one across the boundaries if they are periodic
+fn distance(
+    a: (f64, f64),
+    b: (f64, f64),
+    width: usize,
+    height: usize,
+    periodic: bool,
+    topology: Topology,
+) -> f64 {
+    let (dx, dy) = (a.0 - b.0, a.1 - b.1);
+    let (w, h) = (width as f64, height as f64);
+    let wraps = if periodic { -1..=1 } else { 0..=0 };
+    let mut shortest = f64::INFINITY;
+    for kx in wraps.clone() {
+        for ky in wraps.clone() {
+            let (dx, dy) = (dx + kx as f64 * w, dy + ky as f64 * h);
+            let length = match topology {
+                Topology::Hexagonal => (dx * dx + dx * dy + dy * dy).sqrt(), // (see position)
+                _ => {
+                    let (dx, dy) = topology.position((dx, dy));
+                    dx.hypot(dy)
+                }
+            };
+            shortest = shortest.min(length);
+        }
+    }
+    shortest
+}
+
+#[test]
+fn test_region_grades() {
+    use crate::lattice::Geometry;
+    use rand::SeedableRng;
+
+    let mut rng = SeedableRng::from_seed([123; 32]);
+    let geometry = Geometry { width: 10, height: 8, occupancy: 1.0, ..Geometry::default() };
+    let lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    let nodes_of = |region: Region| {
+        let grades = region.grades(&lattice).unwrap();
+        let mut nodes = grades.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+        nodes.sort();
+        nodes
+    };
+
+    // a unit circle holds a node and its neighbors, also across the boundaries
+    let mut neighborhood = [&[0][..], &lattice.neighborhoods[0][..]].concat();
+    neighborhood.sort();
+    assert_eq!(nodes_of(Region::Circle { x: 0., y: 0., r: 1. }), neighborhood);
+    let rectangle = Region::Rectangle { x0: 1., y0: 2., x1: 3., y1: 2. };
+    assert_eq!(nodes_of(rectangle), [21, 22, 23]);
+    assert!(Region::Cells(vec![5, 80]).grades(&lattice).is_err());
+
+    let linear = Region::Linear { x0: 2., y0: 0., x1: 6., y1: 0. };
+    let grades = linear.grades(&lattice).unwrap();
+    assert_eq!(grades.len(), lattice.capacity());
+    let along_x = [0, 2, 3, 6, 9].map(|i| grades[i].1);
+    assert_eq!(along_x, [0., 0., 0.25, 1., 1.]);
+    let radial = Region::Radial { x: 5., y: 4., r: 2. };
+    let grades = radial.grades(&lattice).unwrap();
+    assert_eq!([grades[45].1, grades[46].1, grades[47].1, grades[48].1], [0., 0.5, 1., 1.]);
+
+    // amounts applied over regions are non-negative
+    let mut lattice = lattice;
+    let circle = Region::Circle { x: 0., y: 0., r: 1. };
+    assert!(crate::commands::set_upper_ifne_in(&mut lattice, (-1., 0.), &circle).is_err());
+    assert!(crate::commands::set_upper_ifne_in(&mut lattice, (0., f64::NAN), &radial).is_err());
+    assert!(lattice.cytokines.iter().all(|&c| c == [0., 0.]));
+}
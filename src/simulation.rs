@@ -5,16 +5,21 @@
 
 use crate::cell::Cell;
 use crate::config::OUT_FILE_NAME_TIME_IN_MIN;
-use crate::event::Event;
-use crate::lattice::{CytokineArray, Lattice};
+use crate::error::{Error, Result};
+use crate::event::{Event, RATE_NAMES};
+use crate::lattice::{CytokineArray, Lattice, Neighborhoods};
+use crate::model::{is_active, Network};
 use crate::molecule::Mol::{Vinf, Vrna, Vprot, Pirf3, Ifni, Pstat, Isg};
 use crate::molecule::N_MOLECULE_SPECIES;
-use crate::rates::{Rates, TIMESTEP};
-use crate::rates::transport::{K_IFNE_LL_DT, K_IFNE_LU_DT, K_IFNE_UL_DT, K_IFNE_UU_DT};
+use crate::output::Output;
+use crate::randomness::Generator;
+use crate::rates::{Rates, TransportScheme};
 use crate::units::{HOUR, MIN};
 
-use rand::{rngs::StdRng, Rng};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::io::Write; // for .flush()
+use std::sync::mpsc::Sender;
 use threadpool::ThreadPool;
 
 #[inline]
@@ -28,23 +33,122 @@ const fn ceil_pow2(i: u32) -> u32 {
     v + 1 + ((v == 0) as u32)
 }
 
-const PROPENS_TREE_SIZE: usize =
-    ceil_pow2(Lattice::CAPACITY as u32) as usize + Lattice::CAPACITY - 1;
-const PROPENS_TREE_CELL_INDEX_BASE: usize = PROPENS_TREE_SIZE - Lattice::CAPACITY;
 const PROPENS_EVENTS_SIZE: usize = 2 * N_MOLECULE_SPECIES + 1; // +molecule,-molecule, and 1 for Die
-type Propensities = [[f64; PROPENS_EVENTS_SIZE]; PROPENS_TREE_SIZE];
+
+// binary tree of partial sums of event propensities; leaves correspond to lattice nodes,
+// and each tree node holds partial sums for all events (stored contiguously)
+struct Propensities {
+    tree: Vec<f64>,
+    n_events: usize,
+    cell_index_base: usize,
+}
+
+impl Propensities {
+    fn new(capacity: usize, n_events: usize) -> Self {
+        let tree_size = ceil_pow2(capacity as u32) as usize + capacity - 1;
+        Propensities {
+            tree: vec![0.; tree_size * n_events],
+            n_events,
+            cell_index_base: tree_size - capacity,
+        }
+    }
+
+    #[inline]
+    fn at(&self, tree_i: usize) -> &[f64] {
+        &self.tree[tree_i * self.n_events..(tree_i + 1) * self.n_events]
+    }
+
+    #[inline]
+    fn at_mut(&mut self, tree_i: usize) -> &mut [f64] {
+        &mut self.tree[tree_i * self.n_events..(tree_i + 1) * self.n_events]
+    }
+}
+
+// constants of transport of a cytokine, multiplied by the time step
+struct TransportDt {
+    q: f64,   // decay
+    sec: f64, // secretion
+    lu: f64,  // lower -> upper
+    ul: f64,  // upper -> lower
+    ll: f64,  // neighbor <-> neighbor, lower
+    uu: f64,  // neighbor <-> neighbor, upper
+}
+
+// relative accuracy of iterative solutions in the implicit transport scheme, and the number of
+// iterations after which a solution is deemed not to converge
+const IMPLICIT_TOLERANCE: f64 = 1e-12;
+const IMPLICIT_MAX_ITERATIONS: usize = 100_000;
+
+// parameter of the TR-BDF2 method (for which both its stages share the matrix of the system)
+const TR_BDF2_GAMMA: f64 = 2. - std::f64::consts::SQRT_2;
+
+// state of a run interrupted before its end, from which the run can be continued exactly
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunProgress {
+    pub t: f64,
+    t_next_ifn: f64,
+    t_next_files_out: f64,
+    t_next_summary_out: f64,
+    propensities: Vec<f64>, // (not recomputed when resuming, as the tree holds rounding errors)
+}
+
+impl RunProgress {
+    pub fn fits(&self, lattice: &Lattice, rates: &Rates) -> bool {
+        let n_events = rates.model.as_ref().map_or(PROPENS_EVENTS_SIZE, |m| m.reactions.len());
+        self.propensities.len() == Propensities::new(lattice.capacity(), n_events).tree.len()
+    }
+}
+
+// Model (if given), secretors of cytokines, and factors of rate constants varying among cells
+// resolved against rates and the lattice, as needed in runs. They are bound anew whenever rates
+// or the lattice change (see Protocol::execute), not in every run.
+#[derive(Debug, Clone)]
+pub struct Kinetics {
+    network: Option<Network>,
+    secretors: Vec<usize>,
+    factor_slots: Vec<Option<usize>>, // of events, in Lattice::rate_factors
+}
+
+impl Kinetics {
+    // (transport is checked for stability on the lattice at hand, see module topology)
+    pub fn bind(rates: &Rates, lattice: &Lattice) -> Result<Self> {
+        rates.check_transport_stability(lattice.neighborhoods.max_exchange_weight())?;
+        let network = rates.model.as_ref().map(|model| model.bind(rates)).transpose()?;
+        let factor_slots = match &network {
+            Some(network) => lattice.rate_factor_slots(network.rate_parameters()),
+            None => lattice.rate_factor_slots(RATE_NAMES.iter().map(|&name| Some(name))),
+        };
+        // (factors of rate constants that no event uses would have no effect)
+        for (slot, rate_factors) in lattice.rate_factors.iter().enumerate() {
+            if !factor_slots.contains(&Some(slot)) {
+                let used_by = if network.is_some() { "reactions of the model" } else { "events" };
+                return Err(Error::InvalidParameter(format!(
+                    "variability of {:?}, which is not a rate constant of {}",
+                    rate_factors.name, used_by
+                )));
+            }
+        }
+        Ok(Kinetics { network, secretors: rates.cytokine_secretors()?, factor_slots })
+    }
+
+    // factor of the rate constant of the event in the cell (1 unless varying among cells)
+    #[inline]
+    fn rate_factor(&self, lattice: &Lattice, event_i: usize, cell_i: usize) -> f64 {
+        self.factor_slots[event_i].map_or(1., |slot| lattice.rate_factors[slot].factors[cell_i])
+    }
+}
 
 pub struct Simulation {}
 
 impl Simulation {
     #[inline]
     fn unset_cell_event_prop(propens: &mut Propensities, cell_i: usize, event_i: usize) {
-        let mut propens_i = PROPENS_TREE_CELL_INDEX_BASE + cell_i;
-        let rate = propens[propens_i][event_i];
+        let mut propens_i = propens.cell_index_base + cell_i;
+        let rate = propens.at(propens_i)[event_i];
         debug_assert!(rate >= 0.);
         if rate > 0. {
             loop {
-                propens[propens_i][event_i] -= rate;
+                propens.at_mut(propens_i)[event_i] -= rate;
                 if propens_i == 0 { break; } else { propens_i = (propens_i - 1) / 2 }
             }
         }
@@ -52,16 +156,16 @@ impl Simulation {
 
     #[inline]
     fn unset_cell_events_props(propens: &mut Propensities, cell_i: usize) {
-        for event_i in 0..PROPENS_EVENTS_SIZE {
+        for event_i in 0..propens.n_events {
             Simulation::unset_cell_event_prop(propens, cell_i, event_i)
         }
     }
 
     #[inline]
     fn set_event_propensity(propens: &mut Propensities, cell_i: usize, event_i: usize, rate: f64) {
-        let mut propens_i = PROPENS_TREE_CELL_INDEX_BASE + cell_i;
+        let mut propens_i = propens.cell_index_base + cell_i;
         loop {
-            propens[propens_i][event_i] += rate;
+            propens.at_mut(propens_i)[event_i] += rate;
             if propens_i == 0 { break; } else { propens_i = (propens_i - 1) / 2 }
         }
     }
@@ -70,16 +174,28 @@ impl Simulation {
         propens: &mut Propensities,
         lattice: &Lattice,
         rates: &Rates,
+        kinetics: &Kinetics,
         cell_i: usize,
         ifni_secretion: bool,
     ) {
         let &cell = &lattice.cells[cell_i];
         if !cell.alive {
             if cfg!(debug_assertions) {
-                for event_i in 0..PROPENS_EVENTS_SIZE {
-                    debug_assert!(
-                        propens[PROPENS_TREE_CELL_INDEX_BASE + cell_i][event_i].abs() < 1.0e-6
-                    );
+                for event_i in 0..propens.n_events {
+                    let leaf = propens.at(propens.cell_index_base + cell_i);
+                    debug_assert!(leaf[event_i].abs() < 1.0e-6);
+                }
+            }
+            return;
+        }
+
+        // the wiring may be given by a model file instead of the code below
+        if let Some(network) = &kinetics.network {
+            for event_i in 0..network.n_events() {
+                let rate_factor = kinetics.rate_factor(lattice, event_i, cell_i);
+                let rate = network.propensity(event_i, lattice, cell_i, rate_factor);
+                if rate > 0. {
+                    Simulation::set_event_propensity(propens, cell_i, event_i, rate);
                 }
             }
             return;
@@ -96,7 +212,9 @@ impl Simulation {
         macro_rules! set_ev_prop {
             ($rxn:ident, $rate_mul:expr, $rate_add:expr) => {
                 let r = Event::$rxn;
-                let rate = r.rate_coef(rates) * $rate_mul + $rate_add;
+                let rate_factor = kinetics.rate_factor(lattice, r.to_index(), cell_i);
+                let rate_k = r.rate_coef(rates) * rate_factor;
+                let rate = rate_k * $rate_mul + $rate_add;
                 Simulation::set_event_propensity(propens, cell_i, r.to_index(), rate);
             };
             ($rxn:ident, $rate_mul:expr) => {
@@ -113,12 +231,15 @@ impl Simulation {
 
         // Vinf
         if can_increase!(Vinf) {
-            for neigh_cell_i in lattice.neighborhoods[cell_i].iter() {
-                let neigh_ms = lattice.cells[*neigh_cell_i].molecules;
+            for (neigh_cell_i, weight) in lattice.neighborhoods.weighted(cell_i) {
+                let neigh_ms = lattice.cells[neigh_cell_i].molecules;
                 if Cell::is_active(Vprot, &neigh_ms) {
-                    set_ev_prop!(VinfIncr);
+                    set_ev_prop!(VinfIncr, weight);
                 }
             }
+            if rates.free_virions() {
+                set_ev_prop!(VinfIncr, 0., rates.k_vire_uptake * lattice.virions[cell_i]);
+            }
         }
         if can_decrease!(Vinf) && (mol_count_!(Vrna) == 0) {
             set_ev_prop!(VinfDecr);
@@ -162,10 +283,12 @@ impl Simulation {
         // Pstat
         if can_increase!(Pstat) {
             let ifne_lo = &lattice.cytokines[cell_i][0];
+            let others = Simulation::other_cytokines_pstat_response(lattice, rates, cell_i);
             // [CONSISTENCY: 0x19cfa3]
             set_ev_prop!(
                 PstatIncr,
-                ifne_lo / (rates.mm_pstat + ifne_lo) / (vprot * rates.vprot_inh_pstat + 1.)
+                (ifne_lo / (rates.mm_pstat + ifne_lo) + others)
+                    / (vprot * rates.vprot_inh_pstat + 1.)
             );
         }
         if can_decrease!(Pstat) {
@@ -187,38 +310,100 @@ impl Simulation {
         //------------------------------------------------------------------------------------------
     }
 
-    fn reset_cells_ifn_events_props(propens: &mut Propensities, lattice: &Lattice, rates: &Rates) {
+    // Updates propensities of events that depend on the medium (IFNe and free virions).
+    // activation of STAT by cytokines other than IFNe (in the lower subcompartment)
+    #[inline]
+    fn other_cytokines_pstat_response(lattice: &Lattice, rates: &Rates, cell_i: usize) -> f64 {
+        let mut response = 0.;
+        for (ci, cytokine) in rates.cytokines.iter().enumerate() {
+            let x = lattice.other_cytokine(ci, cell_i)[0];
+            if x > 0. {
+                response += cytokine.pstat * x / (cytokine.mm_pstat + x)
+            }
+        }
+        response
+    }
+
+    fn reset_cells_medium_events_props(
+        propens: &mut Propensities,
+        lattice: &Lattice,
+        rates: &Rates,
+        kinetics: &Kinetics,
+    ) {
+        if let Some(network) = &kinetics.network {
+            for event_i in (0..network.n_events()).filter(|&ei| network.is_medium_dependent(ei)) {
+                for cell_i in (0..lattice.capacity()).filter(|&ci| lattice.cells[ci].alive) {
+                    Simulation::unset_cell_event_prop(propens, cell_i, event_i);
+                    let rate_factor = kinetics.rate_factor(lattice, event_i, cell_i);
+                    let rate = network.propensity(event_i, lattice, cell_i, rate_factor);
+                    if rate > 0. {
+                        Simulation::set_event_propensity(propens, cell_i, event_i, rate);
+                    }
+                }
+            }
+            return;
+        }
         let r = Event::PstatIncr;
         let (event_i, rate_k) = (r.to_index(), r.rate_coef(rates));
-        for cell_i in 0..Lattice::CAPACITY {
+        for cell_i in 0..lattice.capacity() {
             let &cell = &lattice.cells[cell_i];
             if !cell.alive {
                 continue;
             }
             Simulation::unset_cell_event_prop(propens, cell_i, event_i);
             if Cell::can_increase(Pstat, &cell.molecules) {
+                let rate_k = rate_k * kinetics.rate_factor(lattice, event_i, cell_i);
                 let vprot = cell.molecules[Vprot as usize] as f64;
                 let ifne_lo = lattice.cytokines[cell_i][0];
+                let others = Simulation::other_cytokines_pstat_response(lattice, rates, cell_i);
                 Simulation::set_event_propensity(
                     propens,
                     cell_i,
                     event_i,
-                    rate_k * ifne_lo // [CONSISTENCY:0x19cfa3]
-                    / (rates.mm_pstat + ifne_lo)
+                    (rate_k * ifne_lo // [CONSISTENCY:0x19cfa3]
+                    / (rates.mm_pstat + ifne_lo) + rate_k * others)
                     / (vprot * rates.vprot_inh_pstat + 1.),
                 )
             }
         }
+        if rates.free_virions() {
+            let r = Event::VinfIncr;
+            let (event_i, rate_k) = (r.to_index(), r.rate_coef(rates));
+            for cell_i in (0..lattice.capacity()).filter(|&ci| lattice.cells[ci].alive) {
+                Simulation::unset_cell_event_prop(propens, cell_i, event_i);
+                if Cell::can_increase(Vinf, &lattice.cells[cell_i].molecules) {
+                    // (as in set_cell_events_props)
+                    let rate_k = rate_k * kinetics.rate_factor(lattice, event_i, cell_i);
+                    for (neigh_cell_i, weight) in lattice.neighborhoods.weighted(cell_i) {
+                        if Cell::is_active(Vprot, &lattice.cells[neigh_cell_i].molecules) {
+                            let rate = rate_k * weight;
+                            Simulation::set_event_propensity(propens, cell_i, event_i, rate);
+                        }
+                    }
+                    let uptake = rates.k_vire_uptake * lattice.virions[cell_i];
+                    Simulation::set_event_propensity(propens, cell_i, event_i, uptake);
+                }
+            }
+        }
     }
 
     fn compute_propensities(
         lattice: &Lattice,
         rates: &Rates,
+        kinetics: &Kinetics,
         ifni_secretion: bool,
     ) -> Propensities {
-        let mut propens: Propensities = [[0.; PROPENS_EVENTS_SIZE]; PROPENS_TREE_SIZE];
-        for cell_i in 0..lattice.cells.len() {
-            Simulation::set_cell_events_props(&mut propens, lattice, rates, cell_i, ifni_secretion)
+        let n_events = kinetics.network.as_ref().map_or(PROPENS_EVENTS_SIZE, Network::n_events);
+        let mut propens = Propensities::new(lattice.capacity(), n_events);
+        for cell_i in 0..lattice.capacity() {
+            Simulation::set_cell_events_props(
+                &mut propens,
+                lattice,
+                rates,
+                kinetics,
+                cell_i,
+                ifni_secretion,
+            )
         }
         propens
     }
@@ -227,8 +412,8 @@ impl Simulation {
         // select event class
         let mut acc = 0.;
         let mut event_i = 0;
-        for ei in 0..PROPENS_EVENTS_SIZE {
-            acc += propens[0][ei];
+        for ei in 0..propens.n_events {
+            acc += propens.at(0)[ei];
             if acc > rho {
                 break;
             } else {
@@ -237,14 +422,14 @@ impl Simulation {
         }
 
         // reuse random number
-        let mut rho2 = rho - (acc - propens[0][event_i]);
-        debug_assert!(rho2 < propens[0][event_i]);
+        let mut rho2 = rho - (acc - propens.at(0)[event_i]);
+        debug_assert!(rho2 < propens.at(0)[event_i]);
 
         // select cell
         let mut cell_i = 0; // in-tree
-        while cell_i < PROPENS_TREE_CELL_INDEX_BASE {
+        while cell_i < propens.cell_index_base {
             let next_left = 2 * cell_i + 1;
-            let next_left_psum = propens[next_left][event_i];
+            let next_left_psum = propens.at(next_left)[event_i];
             if rho2 < next_left_psum {
                 cell_i = next_left
             } else {
@@ -253,74 +438,364 @@ impl Simulation {
             }
         }
 
-        debug_assert!(propens[cell_i][event_i] > 0.);
-        (cell_i - PROPENS_TREE_CELL_INDEX_BASE, event_i)
+        debug_assert!(propens.at(cell_i)[event_i] > 0.);
+        (cell_i - propens.cell_index_base, event_i)
     }
 
-    fn ifn_transport_step(lattice: &mut Lattice, rates: &Rates, ifni_secretion: bool) {
-        let prev: CytokineArray = lattice.cytokines.clone();
-        let q_ifne_dt = rates.q_ifne * TIMESTEP;
-        let k_ifn_sec_dt = rates.k_ifn_sec * TIMESTEP;
-        for (cell_i, neighs) in lattice.neighborhoods.iter().enumerate() {
+    // One step of transport of a cytokine, with constants already multiplied by the time step.
+    fn cytokine_transport_step(
+        cytokines: &mut CytokineArray,
+        neighborhoods: &Neighborhoods,
+        k: &TransportDt,
+        secretes: impl Fn(usize) -> bool,
+        scheme: TransportScheme,
+    ) -> Result<()> {
+        if scheme == TransportScheme::Implicit {
+            let local = |cell_i: usize| (0., if secretes(cell_i) { k.sec } else { 0. });
+            let implicit_step = Simulation::cytokine_transport_step_implicit;
+            return implicit_step(cytokines, neighborhoods, k, &local);
+        }
+        let prev: CytokineArray = cytokines.clone();
+        for cell_i in 0..prev.len() {
             let (prev_lo, prev_hi) = (prev[cell_i][0], prev[cell_i][1]);
             let (mut lo, mut hi) = (prev_lo, prev_hi);
 
             // decay
-            lo -= q_ifne_dt * prev_lo;
-            hi -= q_ifne_dt * prev_hi;
+            lo -= k.q * prev_lo;
+            hi -= k.q * prev_hi;
 
             // secretion
-            if ifni_secretion && Cell::is_active(Ifni, &lattice.cells[cell_i].molecules) {
-                debug_assert!(lattice.cells[cell_i].alive);
-                lo += k_ifn_sec_dt;
+            if secretes(cell_i) {
+                lo += k.sec;
             }
 
             // transport: lower -> upper
-            let l2g = K_IFNE_LU_DT * prev_lo;
+            let l2g = k.lu * prev_lo;
             lo -= l2g;
             hi += l2g;
 
             // transport: upper -> lower
-            let g2l = K_IFNE_UL_DT * prev_hi;
+            let g2l = k.ul * prev_hi;
             hi -= g2l;
             lo += g2l;
 
             // transport: inter-neighbor exchange in both lower and upper
-            lo -= (Lattice::N_NEIGHBORS as f64) * K_IFNE_LL_DT * prev_lo;
-            hi -= (Lattice::N_NEIGHBORS as f64) * K_IFNE_UU_DT * prev_hi;
-            for neigh_i in neighs.iter() {
-                let prev_neigh_lo_hi = prev[*neigh_i];
-                lo += K_IFNE_LL_DT * prev_neigh_lo_hi[0];
-                hi += K_IFNE_UU_DT * prev_neigh_lo_hi[1];
+            let exchange_weight = neighborhoods.exchange_weight(cell_i);
+            lo -= exchange_weight * k.ll * prev_lo;
+            hi -= exchange_weight * k.uu * prev_hi;
+            for (neigh_i, weight) in neighborhoods.weighted(cell_i) {
+                let prev_neigh_lo_hi = prev[neigh_i];
+                lo += k.ll * weight * prev_neigh_lo_hi[0];
+                hi += k.uu * weight * prev_neigh_lo_hi[1];
+            }
+            cytokines[cell_i] = [lo, hi]
+        }
+        Ok(())
+    }
+
+    // One step of transport of a cytokine by the TR-BDF2 method (a trapezoidal stage followed by
+    // a BDF2 stage), which is second-order accurate and L-stable, so that time steps may be much
+    // longer than in the explicit scheme. Besides the (uniform) constants, each node may have an
+    // additional loss from the lower subcompartment and secretion into it, given by function
+    // local (with constants already multiplied by the time step).
+    fn cytokine_transport_step_implicit(
+        cytokines: &mut CytokineArray,
+        neighborhoods: &Neighborhoods,
+        k: &TransportDt,
+        local: &dyn Fn(usize) -> (f64, f64),
+    ) -> Result<()> {
+        let (gamma, theta) = (TR_BDF2_GAMMA, TR_BDF2_GAMMA / 2.);
+
+        // trapezoidal stage, to time gamma: x' - theta (A x' + s) = x + theta (A x + s)
+        let mut rhs = cytokines.clone();
+        for cell_i in 0..cytokines.len() {
+            let ([lo, hi], (loss, sec)) = (cytokines[cell_i], local(cell_i));
+            let neighs = || neighborhoods.weighted(cell_i);
+            let neighs_lo: f64 = neighs().map(|(j, weight)| weight * cytokines[j][0]).sum();
+            let neighs_hi: f64 = neighs().map(|(j, weight)| weight * cytokines[j][1]).sum();
+            let exchange_weight = neighborhoods.exchange_weight(cell_i);
+            let d_lo = sec - (k.q + loss + k.lu + exchange_weight * k.ll) * lo + k.ul * hi;
+            let d_hi = k.lu * lo - (k.q + k.ul + exchange_weight * k.uu) * hi;
+            rhs[cell_i] = [
+                lo + theta * (d_lo + k.ll * neighs_lo + sec),
+                hi + theta * (d_hi + k.uu * neighs_hi),
+            ];
+        }
+        let staged = Simulation::implicit_transport_solve(&rhs, neighborhoods, k, theta, local)?;
+
+        // BDF2 stage, to time 1: x'' - theta (A x'' + s) = c' x' - c x
+        let c_staged = 1. / (gamma * (2. - gamma));
+        let c_initial = (1. - gamma).powi(2) * c_staged;
+        for (cell_i, r) in rhs.iter_mut().enumerate() {
+            let (x_staged, x_initial) = (staged[cell_i], cytokines[cell_i]);
+            *r = [
+                c_staged * x_staged[0] - c_initial * x_initial[0] + theta * local(cell_i).1,
+                c_staged * x_staged[1] - c_initial * x_initial[1],
+            ];
+        }
+        *cytokines = Simulation::implicit_transport_solve(&rhs, neighborhoods, k, theta, local)?;
+
+        // (the method is not positivity-preserving; undershoots may occur for very long steps)
+        cytokines.iter_mut().for_each(|c| *c = [c[0].max(0.), c[1].max(0.)]);
+        Ok(())
+    }
+
+    // Solves (I - theta A) x = b, where A is the matrix of transport (see
+    // Simulation::cytokine_transport_step_implicit), by block Gauss-Seidel iterations over nodes
+    // (which converge, as I - theta A is a nonsingular M-matrix, unless amounts or constants are
+    // not finite, or the convergence is too slow, which are errors).
+    fn implicit_transport_solve(
+        b: &CytokineArray,
+        neighborhoods: &Neighborhoods,
+        k: &TransportDt,
+        theta: f64,
+        local: &dyn Fn(usize) -> (f64, f64),
+    ) -> Result<CytokineArray> {
+        let non_finite =
+            || Error::InvalidParameter("non-finite amounts in implicit transport".into());
+        let (a12, a21) = (-theta * k.ul, -theta * k.lu);
+        let diagonals = (0..b.len()).map(|cell_i| {
+            let exchange_weight = neighborhoods.exchange_weight(cell_i);
+            [
+                1. + theta * (k.q + local(cell_i).0 + k.lu + exchange_weight * k.ll),
+                1. + theta * (k.q + k.ul + exchange_weight * k.uu),
+            ]
+        });
+        let diagonals = diagonals.collect::<Vec<_>>();
+        let scale = b.iter().flatten().fold(0., |max: f64, x| max.max(x.abs()));
+        if !scale.is_finite() {
+            return Err(non_finite());
+        }
+        let mut x = b.clone();
+        for _ in 0..IMPLICIT_MAX_ITERATIONS {
+            let mut change: f64 = 0.;
+            for cell_i in 0..x.len() {
+                let neighs = || neighborhoods.weighted(cell_i);
+                let neighs_lo: f64 = neighs().map(|(j, weight)| weight * x[j][0]).sum();
+                let neighs_hi: f64 = neighs().map(|(j, weight)| weight * x[j][1]).sum();
+                let b1 = b[cell_i][0] + theta * k.ll * neighs_lo;
+                let b2 = b[cell_i][1] + theta * k.uu * neighs_hi;
+                let [a11, a22] = diagonals[cell_i];
+                let det = a11 * a22 - a12 * a21;
+                let xi = [(b1 * a22 - a12 * b2) / det, (a11 * b2 - a21 * b1) / det];
+                if !(xi[0].is_finite() && xi[1].is_finite()) {
+                    return Err(non_finite()); // (not caught by the change, as NaNs are skipped)
+                }
+                change = change.max((xi[0] - x[cell_i][0]).abs()).max((xi[1] - x[cell_i][1]).abs());
+                x[cell_i] = xi;
+            }
+            if change <= IMPLICIT_TOLERANCE * scale {
+                return Ok(x);
+            }
+        }
+        Err(Error::InvalidParameter(format!(
+            "implicit transport did not converge in {} iterations",
+            IMPLICIT_MAX_ITERATIONS
+        )))
+    }
+
+    fn ifn_transport_step(
+        lattice: &mut Lattice,
+        rates: &Rates,
+        network: Option<&Network>,
+        ifni_secretion: bool,
+    ) -> Result<()> {
+        let k = TransportDt {
+            q: rates.q_ifne * rates.timestep,
+            sec: rates.k_ifn_sec * rates.timestep,
+            lu: rates.k_ifne_lu * rates.timestep,
+            ul: rates.k_ifne_ul * rates.timestep,
+            ll: rates.k_ifne_ll * rates.timestep,
+            uu: rates.k_ifne_uu * rates.timestep,
+        };
+        let cells = &lattice.cells;
+        let secretes = |cell_i: usize| {
+            let ms = &cells[cell_i].molecules;
+            let secretes = ifni_secretion
+                && network.map_or(Cell::is_active(Ifni, ms), |n| n.secretes_ifn(ms));
+            debug_assert!(!secretes || cells[cell_i].alive);
+            secretes
+        };
+        Simulation::cytokine_transport_step(
+            &mut lattice.cytokines,
+            &lattice.neighborhoods,
+            &k,
+            secretes,
+            rates.transport_scheme,
+        )
+    }
+
+    // Cytokines other than IFNe are secreted by cells with the active species given in rates.
+    fn other_cytokines_transport_step(
+        lattice: &mut Lattice,
+        rates: &Rates,
+        secretors: &[usize],
+    ) -> Result<()> {
+        let model = rates.model.as_deref();
+        for ((cytokine, cytokines), &mi) in
+            rates.cytokines.iter().zip(lattice.other_cytokines.iter_mut()).zip(secretors)
+        {
+            let k = TransportDt {
+                q: cytokine.q * rates.timestep,
+                sec: cytokine.k_sec * rates.timestep,
+                lu: cytokine.k_lu * rates.timestep,
+                ul: cytokine.k_ul * rates.timestep,
+                ll: cytokine.k_ll * rates.timestep,
+                uu: cytokine.k_uu * rates.timestep,
+            };
+            let cells = &lattice.cells;
+            let secretes = |cell_i: usize| {
+                let cell = &cells[cell_i];
+                cell.alive && is_active(model, mi, &cell.molecules)
+            };
+            let neighborhoods = &lattice.neighborhoods;
+            let scheme = rates.transport_scheme;
+            Simulation::cytokine_transport_step(cytokines, neighborhoods, &k, secretes, scheme)?;
+        }
+        Ok(())
+    }
+
+    // Free virions are transported between neighbors as IFNe (in a single subcompartment of the
+    // medium); they are released by cells with active Vprot (or the species given by the model),
+    // decay, and are taken up by alive cells.
+    fn virion_transport_step(
+        lattice: &mut Lattice,
+        rates: &Rates,
+        network: Option<&Network>,
+    ) -> Result<()> {
+        if rates.transport_scheme == TransportScheme::Implicit {
+            return Simulation::virion_transport_step_implicit(lattice, rates, network);
+        }
+        let prev = lattice.virions.clone();
+        let dt = rates.timestep;
+        let (q_vire_dt, k_vir_sec_dt) = (rates.q_vire * dt, rates.k_vir_sec * dt);
+        let (k_vire_uptake_dt, k_vire_exch_dt) = (rates.k_vire_uptake * dt, rates.k_vire_exch * dt);
+        for cell_i in 0..prev.len() {
+            let mut v = prev[cell_i];
+
+            // decay
+            v -= q_vire_dt * prev[cell_i];
+
+            // release and uptake
+            let cell = &lattice.cells[cell_i];
+            if cell.alive {
+                let ms = &cell.molecules;
+                if network.map_or(Cell::is_active(Vprot, ms), |n| n.releases_virions(ms)) {
+                    v += k_vir_sec_dt;
+                }
+                v -= k_vire_uptake_dt * prev[cell_i];
             }
-            lattice.cytokines[cell_i] = [lo, hi]
+
+            // transport: inter-neighbor exchange
+            v -= lattice.neighborhoods.exchange_weight(cell_i) * k_vire_exch_dt * prev[cell_i];
+            for (neigh_i, weight) in lattice.neighborhoods.weighted(cell_i) {
+                v += k_vire_exch_dt * weight * prev[neigh_i];
+            }
+            lattice.virions[cell_i] = v
+        }
+        Ok(())
+    }
+
+    // (see Simulation::cytokine_transport_step_implicit, with virions in the lower
+    // subcompartment)
+    fn virion_transport_step_implicit(
+        lattice: &mut Lattice,
+        rates: &Rates,
+        network: Option<&Network>,
+    ) -> Result<()> {
+        let dt = rates.timestep;
+        let k = TransportDt {
+            q: rates.q_vire * dt,
+            sec: rates.k_vir_sec * dt,
+            lu: 0.,
+            ul: 0.,
+            ll: rates.k_vire_exch * dt,
+            uu: 0.,
+        };
+        let cells = &lattice.cells;
+        let local = |cell_i: usize| {
+            let (cell, ms) = (&cells[cell_i], &cells[cell_i].molecules);
+            match cell.alive {
+                true if network.map_or(Cell::is_active(Vprot, ms), |n| n.releases_virions(ms)) => {
+                    (rates.k_vire_uptake * dt, k.sec)
+                }
+                true => (rates.k_vire_uptake * dt, 0.),
+                false => (0., 0.),
+            }
+        };
+        let mut virions = lattice.virions.iter().map(|&v| [v, 0.]).collect::<CytokineArray>();
+        let neighborhoods = &lattice.neighborhoods;
+        Simulation::cytokine_transport_step_implicit(&mut virions, neighborhoods, &k, &local)?;
+        lattice.virions = virions.iter().map(|v| v[0]).collect();
+        Ok(())
+    }
+
+    // Appends rows of the summary for all sampling times up to the given time (or only before
+    // it, if not inclusive), so that no row is skipped when several sampling intervals elapse
+    // between consecutive events. Rows are labeled with sampling times.
+    fn summary_out_until(
+        t: f64,
+        inclusive: bool,
+        t_next_summary_out: &mut f64,
+        summary_interval: f64,
+        lattice: &Lattice,
+        output: &Output,
+        workers: &Option<(ThreadPool, Sender<Error>)>,
+    ) {
+        while t > *t_next_summary_out || (inclusive && t == *t_next_summary_out) {
+            if let Some(summary) = &output.summary {
+                // (rows are few and cheap, so they are written in order, in this thread)
+                if let Err(err) = summary.lock().unwrap().append(*t_next_summary_out, lattice) {
+                    workers.as_ref().unwrap().1.send(err).unwrap_or(())
+                }
+            }
+            *t_next_summary_out += summary_interval;
         }
     }
 
     pub fn simulate(
         lattice: &mut Lattice,
         rates: &Rates,
-        rng: &mut StdRng,
+        kinetics: &Kinetics, // (bound to the rates)
+        rng: &mut Generator,
         tspan: (f64, f64),
         files_out: bool,
-        images_out: bool,
+        output: &Output,
         files_out_interval: f64,
         ifni_secretion: bool,
         in_sep_thread: bool,
         init_frame_out: bool,
-        workers: &Option<ThreadPool>,
-    ) {
+        resumed: Option<RunProgress>,
+        t_stop: f64, // if reached before the end of the run, progress is returned
+        workers: &Option<(ThreadPool, Sender<Error>)>, // output file writers and their errors
+    ) -> Result<Option<RunProgress>> {
         // (currently, these 3 parameters are redundant)
         debug_assert!(in_sep_thread == workers.is_none());
         debug_assert!(in_sep_thread == !ifni_secretion);
 
-        let mut propens = Simulation::compute_propensities(lattice, rates, ifni_secretion);
+        let (network, secretors) = (kinetics.network.as_ref(), &kinetics.secretors);
+        lattice.fit_cytokines(rates);
+        let mut propens =
+            Simulation::compute_propensities(lattice, rates, kinetics, ifni_secretion);
         let (mut t, mut t_next_ifn, mut t_next_files_out) = (
             tspan.0,
-            tspan.0 + TIMESTEP,
+            tspan.0 + rates.timestep,
             tspan.0 + (if init_frame_out { 0. } else { files_out_interval }),
         );
-        if !in_sep_thread {
+        // (without a summary interval, the summary is sampled only in runs with file output)
+        let summary_interval = output.summary_interval.unwrap_or(files_out_interval);
+        let summary_out = output.summary.is_some()
+            && !in_sep_thread
+            && (output.summary_interval.is_some() || files_out);
+        let mut t_next_summary_out = tspan.0 + (if init_frame_out { 0. } else { summary_interval });
+        if let Some(progress) = resumed {
+            debug_assert!(progress.propensities.len() == propens.tree.len());
+            (t, t_next_ifn, t_next_files_out) =
+                (progress.t, progress.t_next_ifn, progress.t_next_files_out);
+            t_next_summary_out = progress.t_next_summary_out;
+            propens.tree = progress.propensities;
+        }
+        if !in_sep_thread && !output.quiet {
             if OUT_FILE_NAME_TIME_IN_MIN {
                 print!("{:.0}m:", t / MIN);
             } else {
@@ -329,42 +804,83 @@ impl Simulation {
             std::io::stdout().flush().unwrap()
         }
         loop {
+            if t >= t_stop {
+                return Ok(Some(RunProgress {
+                    t,
+                    t_next_ifn,
+                    t_next_files_out,
+                    t_next_summary_out,
+                    propensities: propens.tree,
+                }));
+            }
             // if t >= t_next_print_out && !in_sep_thread { t_next_print_out += 1.*HOUR }
             if files_out && t >= t_next_files_out {
                 if !in_sep_thread {
                     // spawn in a separate thread
-                    print!(".");
-                    std::io::stdout().flush().unwrap();
-                    let (la, rr) = (lattice.clone(), rates.clone());
-                    workers.as_ref().unwrap().execute(move || { la.out(t, &rr, images_out)});
+                    if !output.quiet {
+                        print!(".");
+                        std::io::stdout().flush().unwrap();
+                    }
+                    let (la, rr, oo) = (lattice.clone(), rates.clone(), output.clone());
+                    let (pool, errors) = workers.as_ref().unwrap();
+                    let errors = errors.clone();
+                    pool.execute(move || {
+                        if let Err(err) = la.out(t, &rr, &oo) {
+                            errors.send(err).unwrap_or(())
+                        }
+                    });
                 }
                 t_next_files_out += files_out_interval;
             }
+            if summary_out {
+                let next = &mut t_next_summary_out;
+                Simulation::summary_out_until(
+                    t, true, next, summary_interval, lattice, output, workers,
+                );
+            }
             if t >= tspan.1 {
-                if OUT_FILE_NAME_TIME_IN_MIN {
-                    print!(":{:.0}m ", tspan.1 / MIN);
-                } else {
-                    print!(":{:.0}h ", tspan.1 / HOUR);
+                if !output.quiet {
+                    if OUT_FILE_NAME_TIME_IN_MIN {
+                        print!(":{:.0}m ", tspan.1 / MIN);
+                    } else {
+                        print!(":{:.0}h ", tspan.1 / HOUR);
+                    }
+                    std::io::stdout().flush().unwrap();
                 }
-                std::io::stdout().flush().unwrap();
-                break;
+                return Ok(None);
             }
-            let sum_propens: f64 = propens[0].iter().sum();
+            let sum_propens: f64 = propens.at(0).iter().sum();
             t += -(rng.gen_range(0.0..1.0) as f64).ln() / sum_propens; // exponential variate
+            if summary_out {
+                // (until the next event or transport step, the lattice remains as it is)
+                let (t_jump, next) = (t.min(t_next_ifn).min(tspan.1), &mut t_next_summary_out);
+                Simulation::summary_out_until(
+                    t_jump, false, next, summary_interval, lattice, output, workers,
+                );
+            }
             if t > t_next_ifn {
                 t = t_next_ifn;
-                t_next_ifn += TIMESTEP;
-                Simulation::ifn_transport_step(lattice, rates, ifni_secretion);
-                Simulation::reset_cells_ifn_events_props(&mut propens, lattice, rates);
+                t_next_ifn += rates.timestep;
+                Simulation::ifn_transport_step(lattice, rates, network, ifni_secretion)?;
+                Simulation::other_cytokines_transport_step(lattice, rates, secretors)?;
+                if rates.free_virions() {
+                    Simulation::virion_transport_step(lattice, rates, network)?;
+                }
+                Simulation::reset_cells_medium_events_props(&mut propens, lattice, rates, kinetics);
             } else {
                 let (cell_i, event_i) =
                     Simulation::find_event(&propens, rng.gen_range(0.0..sum_propens));
-                for cell_j in Event::occur(event_i, lattice, cell_i).iter() {
+                let affected_cells = match network {
+                    Some(network) => network.occur(event_i, lattice, cell_i),
+                    None => Event::occur(event_i, lattice, cell_i),
+                };
+                for cell_j in affected_cells.iter() {
                     Simulation::unset_cell_events_props(&mut propens, *cell_j);
                     Simulation::set_cell_events_props(
                         &mut propens,
                         lattice,
                         rates,
+                        kinetics,
                         *cell_j,
                         ifni_secretion,
                     );
@@ -373,3 +889,313 @@ impl Simulation {
         } // loop
     } // simulate()
 }
+
+#[test]
+fn test_default_model_matches_builtin_wiring() {
+    use crate::commands::{add_virus, run_simulation_quietly, set_upper_ifne};
+    use crate::lattice::Geometry;
+    use crate::model::Model;
+    use crate::output::Output;
+    use crate::randomness::initialize_generator;
+    use std::sync::Arc;
+
+    let mut rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    (rates.k_isg0, rates.isg_pro_pirf3, rates.die, rates.vrna_decr) = (1e-4, 0.5, 1e-3, 1e-3);
+    let mut rng = initialize_generator(0x5eed);
+    let geometry = Geometry { width: 30, height: 30, occupancy: 0.9, ..Geometry::default() };
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    set_upper_ifne(&mut lattice, 1.0e4);
+    add_virus(&mut lattice, &rates, &mut rng, 0.2).unwrap();
+    let output = Output::default();
+    run_simulation_quietly(&mut lattice, &rates, &mut rng, (0., 16. * HOUR), &output, false).unwrap();
+
+    let model = Model::from_json_file("models/default.json", &rates).unwrap();
+    let builtin = Kinetics::bind(&rates, &lattice).unwrap();
+    let rates = Rates { model: Some(Arc::new(model)), ..rates };
+    let generic = Kinetics::bind(&rates, &lattice).unwrap();
+    let builtin = Simulation::compute_propensities(&lattice, &rates, &builtin, true);
+    let generic = Simulation::compute_propensities(&lattice, &rates, &generic, true);
+    assert_eq!(builtin.tree.len(), generic.tree.len());
+    assert!(builtin.at(0).iter().filter(|&&p| p > 0.).count() >= 8);
+    for (b, g) in builtin.tree.iter().zip(generic.tree.iter()) {
+        assert!((b - g).abs() <= 1e-9 * b.abs().max(1e-9), "{} vs {}", b, g);
+    }
+}
+
+#[test]
+fn test_free_virions() {
+    use crate::commands::run_simulation_quietly;
+    use crate::lattice::Geometry;
+    use crate::output::Output;
+    use crate::randomness::initialize_generator;
+
+    let mut rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    rates.vinf_incr = 0.; // (no cell-to-cell spread)
+    (rates.k_vir_sec, rates.q_vire, rates.k_vire_uptake, rates.k_vire_exch) = (1., 0., 0., 0.1);
+    let mut rng = initialize_generator(0x5eed);
+    let geometry = Geometry { width: 10, height: 10, occupancy: 1.0, ..Geometry::default() };
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    lattice.virions[0] = 1000.;
+
+    // (without infected cells and uptake, virions are only transported)
+    for _ in 0..1000 {
+        Simulation::virion_transport_step(&mut lattice, &rates, None).unwrap();
+    }
+    assert!((lattice.virions.iter().sum::<f64>() - 1000.).abs() < 1e-9);
+    assert!(lattice.virions[55] > 1.);
+
+    // virions taken up infect cells
+    rates.k_vire_uptake = 1e-3;
+    let output = Output::default();
+    let tspan = (0., 12. * HOUR);
+    run_simulation_quietly(&mut lattice, &rates, &mut rng, tspan, &output, false).unwrap();
+    let infected = lattice.cells.iter().filter(|c| c.molecules[Vinf as usize] > 0).count();
+    assert!(infected > 10);
+
+    let mut csv = Vec::<u8>::new();
+    lattice.write_csv(&rates, &mut csv).unwrap();
+    assert!(csv.starts_with(b"id,alive,Vinf,VRNA,Vprot,pIRF3,IFNi,pSTAT,ISG,IFNeL,IFNeU,VIRe\n"));
+}
+
+#[test]
+fn test_other_cytokines() {
+    use crate::commands::run_simulation_quietly;
+    use crate::lattice::Geometry;
+    use crate::output::Output;
+    use crate::randomness::initialize_generator;
+    use crate::rates::Cytokine;
+
+    let mut rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    rates.cytokines.push(Cytokine {
+        name: "IFNl".into(),
+        secreted_by: "IFNi".into(),
+        k_sec: 0.,
+        q: 0.,
+        k_lu: 0.,
+        k_ul: 0.,
+        k_ll: 0.1,
+        k_uu: 0.,
+        pstat: 1.,
+        mm_pstat: 10.,
+    });
+    let secretors = rates.cytokine_secretors().unwrap();
+    assert_eq!(secretors, [Ifni as usize]);
+    let mut rng = initialize_generator(0x5eed);
+    let geometry = Geometry { width: 10, height: 10, occupancy: 1.0, ..Geometry::default() };
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    lattice.fit_cytokines(&rates);
+    lattice.other_cytokines[0][0] = [1000., 0.];
+
+    // (without secretion and decay, the cytokine is only transported)
+    for _ in 0..1000 {
+        Simulation::other_cytokines_transport_step(&mut lattice, &rates, &secretors).unwrap();
+    }
+    let total = lattice.other_cytokines[0].iter().map(|c| c[0] + c[1]).sum::<f64>();
+    assert!((total - 1000.).abs() < 1e-9);
+    assert!(lattice.other_cytokine(0, 55)[0] > 1.);
+    assert!(lattice.cytokines.iter().all(|c| *c == [0., 0.]));
+
+    // the cytokine alone activates STAT
+    let output = Output::default();
+    let tspan = (0., 2. * HOUR);
+    run_simulation_quietly(&mut lattice, &rates, &mut rng, tspan, &output, false).unwrap();
+    assert!(lattice.cells.iter().any(|c| c.molecules[Pstat as usize] > 0));
+
+    let mut csv = Vec::<u8>::new();
+    lattice.write_csv(&rates, &mut csv).unwrap();
+    let header = b"id,alive,Vinf,VRNA,Vprot,pIRF3,IFNi,pSTAT,ISG,IFNeL,IFNeU,IFNlL,IFNlU\n";
+    assert!(csv.starts_with(header));
+
+    rates.cytokines[0].name = "IFNi".into();
+    assert!(rates.cytokine_secretors().is_err());
+}
+
+#[test]
+fn test_implicit_transport_matches_explicit() {
+    use crate::lattice::Geometry;
+    use crate::randomness::initialize_generator;
+
+    let mut rng = initialize_generator(0x5eed);
+    let geometry = Geometry { width: 30, height: 30, occupancy: 1.0, ..Geometry::default() };
+    let mut initial = Lattice::new(&geometry, &mut rng).unwrap();
+    for cell_i in [0, 77, 78, 465] {
+        initial.cells[cell_i].molecules = Cell::ACT.molecules;
+    }
+    initial.cytokines[300] = [0., 1e5];
+    initial.virions[600] = 1e3;
+    let mut explicit = Rates::from_json_file("parameters/WT.json").unwrap();
+    (explicit.k_vir_sec, explicit.q_vire, explicit.k_vire_uptake) = (1., 1e-3, 1e-3);
+    explicit.k_vire_exch = 0.1;
+    let transported = |rates: &Rates, duration: f64| {
+        let mut lattice = initial.clone();
+        for _ in 0..(duration / rates.timestep).round() as usize {
+            Simulation::ifn_transport_step(&mut lattice, rates, None, true).unwrap();
+            Simulation::virion_transport_step(&mut lattice, rates, None).unwrap();
+        }
+        let ifne = lattice.cytokines.iter().flatten().copied();
+        ifne.chain(lattice.virions.iter().copied()).collect::<Vec<_>>()
+    };
+    let reference = transported(&explicit, 2. * HOUR);
+    let max = reference.iter().copied().fold(0., f64::max);
+    let (mut implicit, rel_tolerances) = (explicit.clone(), [1e-3, 1e-2, 1e-2, 0.25]);
+    implicit.transport_scheme = TransportScheme::Implicit;
+    for (timestep, rel_tolerance) in [1., 10., 30., 2. * HOUR].into_iter().zip(rel_tolerances) {
+        implicit.set("timestep", timestep).unwrap();
+        let amounts = transported(&implicit, 2. * HOUR);
+        let error = reference.iter().zip(amounts.iter()).map(|(a, b)| (a - b).abs());
+        assert!(error.fold(0., f64::max) <= rel_tolerance * max);
+        assert!(amounts.iter().all(|&x| x >= 0.));
+    }
+
+    // (non-finite amounts are reported rather than iterated on)
+    let mut lattice = initial.clone();
+    lattice.cytokines[300] = [0., f64::NAN];
+    assert!(Simulation::ifn_transport_step(&mut lattice, &implicit, None, true).is_err());
+}
+
+#[test]
+fn test_transport_at_boundaries() {
+    use crate::lattice::{Boundary, Geometry};
+    use crate::randomness::initialize_generator;
+
+    let mut rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    rates.q_ifne = 0.;
+    let mut rng = initialize_generator(0x5eed);
+    for scheme in [TransportScheme::Explicit, TransportScheme::Implicit] {
+        rates.transport_scheme = scheme;
+        let mut totals = vec![];
+        for boundary in [Boundary::Periodic, Boundary::Reflecting, Boundary::Absorbing] {
+            let geometry = Geometry { width: 10, height: 10, boundary, ..Geometry::default() };
+            let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+            lattice.cytokines[0] = [0., 1000.];
+            for _ in 0..1000 {
+                Simulation::ifn_transport_step(&mut lattice, &rates, None, false).unwrap();
+            }
+            totals.push(lattice.cytokines.iter().flatten().sum::<f64>());
+        }
+        assert!((totals[0] - 1000.).abs() < 1e-6 && (totals[1] - 1000.).abs() < 1e-6);
+        assert!(totals[2] < 500.);
+    }
+}
+
+#[test]
+fn test_transport_within_mask() {
+    use crate::lattice::Geometry;
+    use crate::randomness::initialize_generator;
+
+    let mut rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    rates.q_ifne = 0.;
+    let mut rng = initialize_generator(0x5eed);
+    let mask = Some("circle r=3".to_string());
+    let geometry = Geometry { width: 10, height: 10, mask, ..Geometry::default() };
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    let n_inside = lattice.n_inside();
+    assert!(n_inside > 20 && n_inside < 50);
+    assert_eq!(lattice.cells.iter().filter(|c| c.alive).count(), n_inside);
+    let center = 4 + 4 * 10;
+    assert!(!lattice.outside[center] && lattice.outside[0]);
+    lattice.cytokines[center] = [0., 1000.];
+    lattice.cells[center].molecules = Cell::ACT.molecules;
+    for _ in 0..1000 {
+        Simulation::ifn_transport_step(&mut lattice, &rates, None, true).unwrap();
+    }
+    let outside = (0..lattice.capacity()).filter(|&i| lattice.outside[i]);
+    assert!(outside.clone().all(|i| lattice.cytokines[i] == [0., 0.]));
+    assert!(lattice.neighborhoods.iter().all(|nbs| nbs.iter().all(|&j| !lattice.outside[j])));
+    let total = lattice.cytokines.iter().flatten().sum::<f64>();
+    let secreted = rates.k_ifn_sec * 1000. * rates.timestep;
+    assert!((total - 1000. - secreted).abs() < 1e-6 * total);
+}
+
+#[test]
+fn test_rate_factors_of_cells() {
+    use crate::commands::{add_virus, run_simulation_quietly};
+    use crate::lattice::Geometry;
+    use crate::output::Output;
+    use crate::model::Model;
+    use crate::randomness::initialize_generator;
+    use crate::variability::Variability;
+    use std::sync::Arc;
+
+    let mut rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    rates.vinf_incr = 0.; // (no cell-to-cell spread)
+    let mut rng = initialize_generator(0x5eed);
+    let mut geometry = Geometry { width: 10, height: 10, occupancy: 1.0, ..Geometry::default() };
+    geometry.variability.insert("vrna_incr".into(), Variability::Gamma { cv: 0.5 });
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    let kinetics = Kinetics::bind(&rates, &lattice).unwrap();
+    assert!(kinetics.rate_factor(&lattice, Event::VrnaIncr.to_index(), 7) != 1.);
+    assert_eq!(kinetics.rate_factor(&lattice, Event::VprotIncr.to_index(), 7), 1.);
+    // (only rate constants used by the kinetics may vary)
+    let mut other_geometry = geometry.clone();
+    other_geometry.variability.insert("k_isg0".into(), Variability::Gamma { cv: 0.1 });
+    let other_lattice = Lattice::new(&other_geometry, &mut rng).unwrap();
+    assert!(Kinetics::bind(&rates, &other_lattice).is_err());
+    let text = r#"{"species": [{"name": "X", "max": 1, "active": 1}], "reactions": [
+        {"name": "XIncr", "effect": {"increase": "X"}, "rate": "k_isg0"}]}"#;
+    let model = serde_json::from_str::<Model>(text).unwrap();
+    let model_rates = Rates { model: Some(Arc::new(model)), ..rates.clone() };
+    other_geometry.variability.remove("vrna_incr");
+    let other_lattice = Lattice::new(&other_geometry, &mut rng).unwrap();
+    assert!(Kinetics::bind(&model_rates, &other_lattice).is_ok());
+    assert!(Kinetics::bind(&model_rates, &lattice).is_err());
+
+    // cells of zero factors do not replicate viral RNA
+    let factors = &mut lattice.rate_factors[0].factors;
+    factors.iter_mut().step_by(2).for_each(|factor| *factor = 0.);
+    add_virus(&mut lattice, &rates, &mut rng, 5.).unwrap();
+    let (output, tspan) = (Output::default(), (0., 4. * HOUR));
+    run_simulation_quietly(&mut lattice, &rates, &mut rng, tspan, &output, false).unwrap();
+    let vrna = |cell_i: usize| lattice.cells[cell_i].molecules[Vrna as usize];
+    assert!((0..100).step_by(2).all(|cell_i| vrna(cell_i) == 0));
+    assert!((1..100).step_by(2).any(|cell_i| vrna(cell_i) > 0));
+
+    let mut csv = Vec::<u8>::new();
+    lattice.write_csv(&rates, &mut csv).unwrap();
+    assert!(String::from_utf8(csv).unwrap().lines().next().unwrap().ends_with(",vrna_incr_factor"));
+}
+
+#[test]
+fn test_model_species_found_by_name() {
+    use crate::commands::{add_virus, remove_free_virus, run_simulation_quietly};
+    use crate::lattice::Geometry;
+    use crate::model::Model;
+    use crate::output::Output;
+    use crate::randomness::initialize_generator;
+    use std::sync::Arc;
+
+    // (more species than built-in molecules, with those of the virus in the last slots)
+    let species = (0..7).map(|i| format!(r#"{{"name": "X{}", "max": 2, "active": 1}}"#, i));
+    let species = species.collect::<Vec<_>>().join(", ");
+    let text = [
+        r#"{"species": ["#,
+        &species,
+        r#", {"name": "Vinf", "max": 1, "active": 1}, {"name": "VRNA", "max": 3, "active": 1}],
+          "reactions": [
+            {"name": "VrnaIncr", "effect": {"increase": "VRNA"}, "rate": "vrna_incr",
+             "when": [{"active": "Vinf"}]},
+            {"name": "X6Incr", "effect": {"increase": "X6"}, "rate": "isg_incr",
+             "when": [{"active": "VRNA"}]}]}"#,
+    ]
+    .concat();
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let model = serde_json::from_str::<Model>(&text).unwrap();
+    let rates = Rates { model: Some(Arc::new(model)), ..rates };
+    let mut rng = initialize_generator(0x5eed);
+    let geometry = Geometry { width: 10, height: 10, occupancy: 1.0, ..Geometry::default() };
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    add_virus(&mut lattice, &rates, &mut rng, 0.5).unwrap();
+    assert!(lattice.cells.iter().any(|c| c.molecules[7] == 1));
+    assert!(lattice.cells.iter().all(|c| c.molecules[Vinf as usize] == 0));
+    let (output, tspan) = (Output::default(), (0., 8. * HOUR));
+    run_simulation_quietly(&mut lattice, &rates, &mut rng, tspan, &output, false).unwrap();
+    assert!(lattice.cells.iter().any(|c| c.molecules[8] > 0 && c.molecules[6] > 0));
+    remove_free_virus(&mut lattice, &rates).unwrap();
+    assert!(lattice.cells.iter().all(|c| c.molecules[7] == 0 || c.molecules[8] > 0));
+
+    // (infection needs the species of the virus)
+    let text = r#"{"species": [{"name": "X", "max": 1, "active": 1}], "reactions": []}"#;
+    let model = serde_json::from_str::<Model>(text).unwrap();
+    let rates = Rates { model: Some(Arc::new(model)), ..rates };
+    assert!(add_virus(&mut lattice, &rates, &mut rng, 0.5).is_err());
+}
@@ -0,0 +1,154 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Population-level time course: one CSV row per sampling time, with counts of alive and dead
+// cells, counts of cells in which each molecule is active (see Cell::is_active, or activity
+// thresholds of the model, if given), and total, mean and max of IFNe in the lower and upper
+// subcompartment (followed by other cytokines, if given, and by free virions, if simulated).
+// Lattice nodes not occupied by cells are counted as dead, unless they are outside the domain
+// (see module mask), over which means are also computed.
+
+use crate::cell::Cell;
+use crate::error::Result;
+use crate::lattice::Lattice;
+use crate::model::{is_active, species_names, Model};
+use crate::rates::Rates;
+
+use std::io::Write;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct SummaryWriter<W: Write> {
+    out: W,
+    model: Option<Arc<Model>>,
+    n_species: usize,
+    n_other_cytokines: usize,
+    free_virions: bool,
+}
+
+impl<W: Write> SummaryWriter<W> {
+    pub fn new(mut out: W, rates: &Rates) -> Result<Self> {
+        let species_names = species_names(rates.model.as_deref());
+        let mut fields = vec!["IFNeL".to_string(), "IFNeU".to_string()];
+        for cytokine in rates.cytokines.iter() {
+            fields.extend([cytokine.name.clone() + "L", cytokine.name.clone() + "U"])
+        }
+        if rates.free_virions() {
+            fields.push("VIRe".to_string())
+        }
+        let mut header = ["time_min,alive,dead,", &species_names.join(",")].concat();
+        for field in fields {
+            header.push_str(&format!(",{0}_total,{0}_mean,{0}_max", field))
+        }
+        header.push('\n');
+        out.write_all(header.as_bytes())?;
+        Ok(SummaryWriter::appending(out, rates))
+    }
+
+    // for a file that already has the header (of the same species)
+    pub fn appending(out: W, rates: &Rates) -> Self {
+        let n_species = species_names(rates.model.as_deref()).len();
+        let (n_other_cytokines, free_virions) = (rates.cytokines.len(), rates.free_virions());
+        let model = rates.model.clone();
+        SummaryWriter { out, model, n_species, n_other_cytokines, free_virions }
+    }
+
+    pub fn append(&mut self, time: f64, lattice: &Lattice) -> Result<()> {
+        let n_alive = lattice.cells.iter().filter(|c| c.alive).count();
+        let n_inside = lattice.n_inside();
+        let mut line: Vec<String> =
+            vec![format!("{:.3}", time), n_alive.to_string(), (n_inside - n_alive).to_string()];
+        let model = self.model.as_deref();
+        for mi in 0..self.n_species {
+            let is_active_in = |c: &&Cell| c.alive && is_active(model, mi, &c.molecules);
+            let n_active = lattice.cells.iter().filter(is_active_in).count();
+            line.push(n_active.to_string())
+        }
+        let mut fields = vec![
+            lattice.cytokines.iter().map(|c| c[0]).collect::<Vec<_>>(),
+            lattice.cytokines.iter().map(|c| c[1]).collect(),
+        ];
+        for ci in 0..self.n_other_cytokines {
+            for j in 0..=1 {
+                let amounts = (0..lattice.capacity()).map(|i| lattice.other_cytokine(ci, i)[j]);
+                fields.push(amounts.collect())
+            }
+        }
+        if self.free_virions {
+            fields.push(lattice.virions.clone());
+        }
+        for field in fields {
+            let total: f64 = field.iter().sum();
+            let max = field.iter().copied().fold(0., f64::max);
+            line.push(format!("{:.3e}", total));
+            line.push(format!("{:.3e}", total / n_inside as f64));
+            line.push(format!("{:.3e}", max));
+        }
+        let mut line_s = line.join(",");
+        line_s.push('\n');
+        self.out.write_all(line_s.as_bytes())?;
+        Ok(self.out.flush()?)
+    }
+}
+
+#[test]
+fn test_summary_counts() {
+    use crate::lattice::Geometry;
+    use crate::molecule::Mol;
+    use rand::SeedableRng;
+
+    let mut rng = SeedableRng::from_seed([123; 32]);
+    let geometry = Geometry { width: 4, height: 3, occupancy: 1.0, ..Geometry::default() };
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    lattice.cells[0].alive = false;
+    lattice.cells[1].molecules[Mol::Vinf as usize] = 1;
+    lattice.cells[2].molecules = Cell::ACT.molecules;
+    lattice.cytokines[5] = [1.5, 12.];
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let mut summary = SummaryWriter::new(Vec::<u8>::new(), &rates).unwrap();
+    summary.append(120., &lattice).unwrap();
+
+    let text = String::from_utf8(summary.out).unwrap();
+    let lines = text.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+    let row = lines[1].split(',').collect::<Vec<_>>();
+    assert_eq!(row[..10], ["120.000", "11", "1", "2", "1", "1", "1", "1", "1", "1"]);
+    let values = row[10..].iter().map(|v| v.parse::<f64>().unwrap()).collect::<Vec<_>>();
+    assert_eq!(values, [1.5, 0.125, 1.5, 12., 1., 12.]);
+}
+
+#[test]
+fn test_summary_sampling_within_steps() {
+    use crate::commands::{add_virus, run_simulation_quietly};
+    use crate::lattice::Geometry;
+    use crate::output::Output;
+    use crate::randomness::initialize_generator;
+    use crate::units::{HOUR, MIN};
+
+    // (also in runs without file output, and with intervals shorter than the transport step)
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    let dir = std::env::temp_dir().join(format!("visavis-summary-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let interval = MIN / 32.;
+    assert!(interval < rates.timestep);
+    let summary_interval = Some(interval);
+    let mut output =
+        Output { dir: dir.clone(), summary_interval, quiet: true, ..Output::default() };
+    output.open_summary_file(&rates, None).unwrap();
+    let geometry = Geometry { width: 10, height: 10, occupancy: 1.0, ..Geometry::default() };
+    let mut rng = initialize_generator(0x5eed);
+    let mut lattice = Lattice::new(&geometry, &mut rng).unwrap();
+    add_virus(&mut lattice, &rates, &mut rng, 0.5).unwrap();
+    run_simulation_quietly(&mut lattice, &rates, &mut rng, (0., HOUR), &output, true).unwrap();
+
+    let text = std::fs::read_to_string(output.file_path("summary.csv")).unwrap();
+    let times = text.lines().skip(1).map(|line| line.split(',').next().unwrap().to_string());
+    let expected = (0..).map(|i| format!("{:.3}", i as f64 * interval)).take_while(|t| {
+        t.parse::<f64>().unwrap() <= HOUR
+    });
+    assert_eq!(times.collect::<Vec<_>>(), expected.collect::<Vec<_>>());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
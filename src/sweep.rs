@@ -0,0 +1,190 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Parameter sweeps. A sweep file (JSON) gives the base parameters file (relative to the sweep
+// file) and values of rates to be scanned, for example:
+//
+//   { "parameters": "WT.json",
+//     "combination": "cartesian",
+//     "values": { "k_ifn_sec": [4000, 8000],
+//                 "vprot_inh_pstat": { "linspace": [0.5, 2.5, 5] },
+//                 "isg_incr": { "logspace": [-4, -2, 3] } } }
+//
+// Ranges are given as (start, stop, number of values), with logspace values ranging from 10^start
+// to 10^stop. Values of all rates are either combined in all possible ways ("cartesian", the
+// default; the rate named last in alphabetical order varies fastest), or "zip"ped together
+// (then all rates have to be given the same number of values).
+
+use crate::error::{Error, Result};
+use crate::rates::Rates;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Combination {
+    #[default]
+    Cartesian,
+    Zip,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Range {
+    Linspace(f64, f64, usize),
+    Logspace(f64, f64, usize),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Values {
+    List(Vec<f64>),
+    Range(Range),
+}
+
+impl Values {
+    pub fn expand(&self) -> Vec<f64> {
+        let spaced = |start: f64, stop: f64, n: usize| {
+            let step = if n > 1 { (stop - start) / (n - 1) as f64 } else { 0. };
+            (0..n).map(move |i| if i + 1 == n && n > 1 { stop } else { start + step * i as f64 })
+        };
+        match *self {
+            Values::List(ref values) => values.clone(),
+            Values::Range(Range::Linspace(start, stop, n)) => spaced(start, stop, n).collect(),
+            Values::Range(Range::Logspace(start, stop, n)) => {
+                spaced(start, stop, n).map(|exponent| 10_f64.powf(exponent)).collect()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Sweep {
+    pub parameters: PathBuf,
+    #[serde(default)]
+    pub combination: Combination,
+    pub values: BTreeMap<String, Values>,
+    #[serde(skip)]
+    base: serde_json::Value, // contents of the parameters file
+}
+
+impl Sweep {
+    pub fn from_json_file<P: AsRef<Path>>(sweep_file_path: P) -> Result<Self> {
+        let contents = fs::read_to_string(sweep_file_path.as_ref())?;
+        let mut sweep: Sweep = serde_json::from_str(&contents)?;
+        let base_dir = sweep_file_path.as_ref().parent().unwrap_or(Path::new(""));
+        sweep.parameters = base_dir.join(&sweep.parameters);
+        sweep.base = serde_json::from_str(&fs::read_to_string(&sweep.parameters)?)?;
+
+        let invalid = |message: String| {
+            Err(Error::InvalidParameter(["sweep: ", &message].concat()))
+        };
+        let rates = serde_json::to_value(Rates::from_json_file(&sweep.parameters)?)?;
+        if let Some(field) = sweep.fields().into_iter().find(|field| rates.get(field).is_none()) {
+            return invalid(format!("{:?} is not a rate", field));
+        }
+        let lengths = sweep.values.values().map(|values| values.expand().len()).collect::<Vec<_>>();
+        if lengths.is_empty() || lengths.contains(&0) {
+            return invalid("no values".to_string());
+        }
+        if sweep.combination == Combination::Zip && lengths.iter().any(|&n| n != lengths[0]) {
+            return invalid("zipped rates given different numbers of values".to_string());
+        }
+        Ok(sweep)
+    }
+
+    // names of scanned rates, in the order of values of points
+    pub fn fields(&self) -> Vec<&str> {
+        self.values.keys().map(String::as_str).collect()
+    }
+
+    // values of the scanned rates in all points of the sweep
+    pub fn points(&self) -> Vec<Vec<f64>> {
+        let values = self.values.values().map(Values::expand).collect::<Vec<_>>();
+        match self.combination {
+            Combination::Zip => {
+                (0..values[0].len()).map(|i| values.iter().map(|vs| vs[i]).collect()).collect()
+            }
+            Combination::Cartesian => values.iter().fold(vec![vec![]], |points, vs| {
+                let extended = |point: &Vec<f64>| {
+                    vs.iter().map(|&v| [&point[..], &[v]].concat()).collect::<Vec<_>>()
+                };
+                points.iter().flat_map(extended).collect()
+            }),
+        }
+    }
+
+    // contents of the base parameters file, with scanned rates set to the values of the point
+    pub fn parameters_at(&self, point: &[f64]) -> serde_json::Value {
+        let mut parameters = self.base.clone();
+        for (field, &value) in self.fields().into_iter().zip(point) {
+            parameters[field] = serde_json::json!(value);
+        }
+        parameters
+    }
+
+    pub fn rates_at(&self, point: &[f64]) -> Result<Rates> {
+        let rates: Rates = serde_json::from_value(self.parameters_at(point))?;
+        rates.check_transport_constants()?;
+        Ok(rates)
+    }
+}
+
+// Writes a single table of summaries (see module summary) of runs of the sweep, in which each row
+// of a summary is preceded by the index of the point and of the replicate, and by the values of
+// the scanned rates.
+pub fn write_results<P: AsRef<Path>, W: Write>(
+    fields: &[&str],
+    runs: &[(usize, usize, Vec<f64>, P)], // point and replicate, point, summary path
+    mut out: W,
+) -> Result<()> {
+    let mut header_written = false;
+    for (point_i, replicate_i, point, summary_path) in runs {
+        let summary = fs::read_to_string(summary_path.as_ref())?;
+        let mut lines = summary.lines();
+        let summary_header = lines.next().unwrap_or("");
+        if !header_written {
+            let header = ["point,replicate,", &fields.join(","), ",", summary_header, "\n"];
+            out.write_all(header.concat().as_bytes())?;
+            header_written = true;
+        }
+        let mut key = vec![point_i.to_string(), replicate_i.to_string()];
+        key.extend(point.iter().map(|value| value.to_string()));
+        let key = key.join(",");
+        for line in lines {
+            out.write_all([&key, ",", line, "\n"].concat().as_bytes())?;
+        }
+    }
+    Ok(out.flush()?)
+}
+
+#[test]
+fn test_sweep_expansion() {
+    let json = r#"{ "parameters": "WT.json",
+                    "values": { "k_ifn_sec": [1, 2],
+                                "vprot_inh_pstat": { "linspace": [0.5, 2.5, 3] },
+                                "isg_incr": { "logspace": [-2, 0, 3] } } }"#;
+    let mut sweep: Sweep = serde_json::from_str(json).unwrap();
+    assert_eq!(sweep.fields(), ["isg_incr", "k_ifn_sec", "vprot_inh_pstat"]);
+    let points = sweep.points();
+    assert_eq!(points.len(), 3 * 2 * 3);
+    assert_eq!(points[0], [0.01, 1., 0.5]);
+    assert_eq!(points[1], [0.01, 1., 1.5]);
+    assert_eq!(points[17], [1., 2., 2.5]);
+
+    sweep.combination = Combination::Zip;
+    sweep.values.remove("k_ifn_sec");
+    assert_eq!(sweep.points(), [[0.01, 0.5], [0.1, 1.5], [1., 2.5]]);
+
+    let rates = Rates::from_json_file("parameters/WT.json").unwrap();
+    sweep.base = serde_json::to_value(rates).unwrap();
+    let rates = sweep.rates_at(&[0.1, 1.5]).unwrap();
+    assert_eq!((rates.isg_incr, rates.vprot_inh_pstat), (0.1, 1.5));
+}
@@ -0,0 +1,348 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Topologies of the lattice, which determine neighbors of nodes and weights of their contacts
+// (used in transport of the medium contents and in infection of neighbors). Node (x, y), of
+// index x + y * width, has coordinates (x, y) in regular lattices; in the irregular (Voronoi)
+// tissue, cells are Voronoi cells of seed points, one drawn uniformly from the unit square
+// around each node (x, y), and neighbors are cells sharing an edge, with weights proportional
+// to its length. Weights are scaled so that the mean total contact of a node (its perimeter,
+// including, at the edges, its contact with the surroundings) is Lattice::N_NEIGHBORS, as in
+// the hexagonal lattice, in which, as in the square lattice with 4 neighbors, all weights are 1.
+// (Total contacts of some Voronoi cells are thus larger, see Rates::check_transport_stability.)
+
+use crate::error::{Error, Result};
+use crate::lattice::Lattice;
+use crate::randomness::Generator;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    #[default]
+    Hexagonal, // neighbors E, W, S, N, SW, NE (the lattice is a parallelogram)
+    Square4,   // neighbors E, W, S, N
+    Square8,   // neighbors E, W, S, N, SW, NE, SE, NW (touching at a corner, see DIAGONAL_WEIGHT)
+    Voronoi,   // irregular tissue of Voronoi cells
+}
+
+impl FromStr for Topology {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hexagonal" => Ok(Topology::Hexagonal),
+            "square4" => Ok(Topology::Square4),
+            "square8" => Ok(Topology::Square8),
+            "voronoi" => Ok(Topology::Voronoi),
+            _ => Err(Error::InvalidParameter(format!("topology {:?}", s))),
+        }
+    }
+}
+
+impl Topology {
+    // weight of contact of diagonal neighbors (relative to that of neighbors sharing a side)
+    pub const DIAGONAL_WEIGHT: f64 = 0.5;
+
+    // (relative to node (x, y), with weights of contact; Voronoi tissue has no fixed offsets)
+    fn offsets(self) -> &'static [(isize, isize, f64)] {
+        const D: f64 = Topology::DIAGONAL_WEIGHT;
+        match self {
+            Topology::Hexagonal => {
+                &[(1, 0, 1.), (-1, 0, 1.), (0, 1, 1.), (0, -1, 1.), (-1, 1, 1.), (1, -1, 1.)]
+            }
+            Topology::Square4 => &[(1, 0, 1.), (-1, 0, 1.), (0, 1, 1.), (0, -1, 1.)],
+            Topology::Square8 => &[
+                (1, 0, 1.), (-1, 0, 1.), (0, 1, 1.), (0, -1, 1.),
+                (-1, 1, D), (1, -1, D), (1, 1, D), (-1, -1, D),
+            ],
+            Topology::Voronoi => &[],
+        }
+    }
+
+    // Position in the plane (with unit spacing of neighbors sharing a side) of the point of
+    // coordinates (x, y); the mapping is linear, so that it applies also to displacements.
+    pub fn position(self, (x, y): (f64, f64)) -> (f64, f64) {
+        match self {
+            Topology::Hexagonal => (x + y / 2., y * 3_f64.sqrt() / 2.),
+            Topology::Square4 | Topology::Square8 | Topology::Voronoi => (x, y),
+        }
+    }
+}
+
+// Neighbors of a node with weights of their contact, and the weight of contact with the
+// surroundings of the domain (beyond the edges of the lattice or nodes outside the mask).
+#[derive(Debug, Clone, Default)]
+pub struct Contacts {
+    pub neighbors: Vec<(usize, f64)>,
+    pub beyond: f64,
+}
+
+// Contacts of nodes of a regular lattice. With the periodic boundary, coordinates wrap around
+// the edges, otherwise neighbors beyond the edges are omitted, as are nodes outside the domain
+// (which have no contacts).
+pub fn grid_contacts(
+    topology: Topology,
+    width: usize,
+    height: usize,
+    periodic: bool,
+    outside: &[bool],
+) -> Vec<Contacts> {
+    let offsets = topology.offsets();
+    let full_weight = offsets.iter().map(|&(_, _, weight)| weight).sum::<f64>();
+    let (w, h) = (width as isize, height as isize);
+    let as_index = |x: isize, y: isize| -> usize { (x + y * w) as usize };
+    (0..width * height)
+        .map(|i| {
+            if outside[i] {
+                return Contacts::default();
+            }
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            let neighbor = |&(dx, dy, weight): &(isize, isize, f64)| {
+                let (x, y) = (x + dx, y + dy);
+                match periodic {
+                    true => Some((as_index(x.rem_euclid(w), y.rem_euclid(h)), weight)),
+                    false if (0..w).contains(&x) && (0..h).contains(&y) => {
+                        Some((as_index(x, y), weight))
+                    }
+                    false => None,
+                }
+            };
+            let neighbors = offsets.iter().filter_map(neighbor).filter(|&(j, _)| !outside[j]);
+            let neighbors = neighbors.collect::<Vec<_>>();
+            let beyond = full_weight - neighbors.iter().map(|&(_, weight)| weight).sum::<f64>();
+            Contacts { neighbors, beyond }
+        })
+        .collect()
+}
+
+// number of raster points per unit length, in each direction (see voronoi_raster)
+pub const VORONOI_RASTER: usize = 8;
+
+// Draws a seed point uniformly from the unit square around each node.
+pub fn voronoi_seeds(width: usize, height: usize, rng: &mut Generator) -> Vec<[f64; 2]> {
+    (0..width * height)
+        .map(|i| {
+            let (x, y) = ((i % width) as f64, (i / width) as f64);
+            [x + rng.gen::<f64>() - 0.5, y + rng.gen::<f64>() - 0.5]
+        })
+        .collect()
+}
+
+// displacement from point a to point b, the shortest one across the boundaries if periodic
+fn displacement(a: [f64; 2], b: [f64; 2], width: usize, height: usize, periodic: bool) -> [f64; 2] {
+    let wrap = |d: f64, length: f64| if periodic { d - length * (d / length).round() } else { d };
+    [wrap(b[0] - a[0], width as f64), wrap(b[1] - a[1], height as f64)]
+}
+
+// Node of the seed nearest to each point of the raster covering the lattice, of
+// VORONOI_RASTER² points per node, row after row, with point (a, b) located at
+// ((a + 1/2) / VORONOI_RASTER - 1/2, (b + 1/2) / VORONOI_RASTER - 1/2).
+pub fn voronoi_raster(
+    seeds: &[[f64; 2]],
+    width: usize,
+    height: usize,
+    periodic: bool,
+) -> Vec<usize> {
+    const R: usize = VORONOI_RASTER;
+    let (w, h) = (width as isize, height as isize);
+    let mut owners = Vec::with_capacity(R * R * width * height);
+    for b in 0..R * height {
+        for a in 0..R * width {
+            let p = [(a as f64 + 0.5) / R as f64 - 0.5, (b as f64 + 0.5) / R as f64 - 0.5];
+            let (x, y) = ((a / R) as isize, (b / R) as isize);
+            // (the seed of the node is within distance √2 from the point, whereas seeds of nodes
+            // farther than 2 nodes away in either direction are at distances of at least 2)
+            let mut nearest = (f64::INFINITY, 0);
+            for (dx, dy) in (-2..=2).flat_map(|dx| (-2..=2).map(move |dy| (dx, dy))) {
+                let (x, y) = match periodic {
+                    true => ((x + dx).rem_euclid(w), (y + dy).rem_euclid(h)),
+                    false => (x + dx, y + dy),
+                };
+                if !((0..w).contains(&x) && (0..h).contains(&y)) {
+                    continue;
+                }
+                let j = (x + y * w) as usize;
+                let [dx, dy] = displacement(p, seeds[j], width, height, periodic);
+                let distance2 = dx * dx + dy * dy;
+                if distance2 < nearest.0 {
+                    nearest = (distance2, j)
+                }
+            }
+            owners.push(nearest.1)
+        }
+    }
+    owners
+}
+
+// Contacts of nodes of the Voronoi tissue. Neighbors are found on the raster (see
+// voronoi_raster), whereas lengths of shared edges are computed exactly; contacts with the
+// surroundings at the edges of the lattice are measured on the raster.
+pub fn voronoi_contacts(
+    seeds: &[[f64; 2]],
+    width: usize,
+    height: usize,
+    periodic: bool,
+    outside: &[bool],
+) -> Vec<Contacts> {
+    const R: usize = VORONOI_RASTER;
+    let owners = voronoi_raster(seeds, width, height, periodic);
+    let (raster_width, raster_height) = (R * width, R * height);
+    let mut adjacent = vec![BTreeSet::new(); width * height];
+    let mut at_edges = vec![0.; width * height];
+    for b in 0..raster_height {
+        for a in 0..raster_width {
+            let i = owners[a + b * raster_width];
+            if !periodic && (a == 0 || a + 1 == raster_width || b == 0 || b + 1 == raster_height) {
+                let n_sides = [a == 0, a + 1 == raster_width, b == 0, b + 1 == raster_height];
+                at_edges[i] += n_sides.iter().filter(|&&side| side).count() as f64 / R as f64;
+            }
+            let (right, below) = ((a + 1) % raster_width, (b + 1) % raster_height);
+            let mut next = vec![];
+            if periodic || right > 0 {
+                next.push(owners[right + b * raster_width])
+            }
+            if periodic || below > 0 {
+                next.push(owners[a + below * raster_width])
+            }
+            for j in next.into_iter().filter(|&j| j != i) {
+                adjacent[i].insert(j);
+                adjacent[j].insert(i);
+            }
+        }
+    }
+
+    // lengths of edges shared with neighbors, and of edges exposed to the surroundings
+    let contacts = at_edges.iter().map(|&beyond| Contacts { neighbors: Vec::new(), beyond });
+    let mut contacts = contacts.collect::<Vec<_>>();
+    for i in 0..width * height {
+        for &j in adjacent[i].range(i + 1..) {
+            let others = adjacent[i].union(&adjacent[j]).filter(|&&k| k != i && k != j);
+            let length = voronoi_edge_length(seeds, i, j, others, width, height, periodic);
+            match (outside[i], outside[j]) {
+                (false, false) if length > 0. => {
+                    contacts[i].neighbors.push((j, length));
+                    contacts[j].neighbors.push((i, length));
+                }
+                (false, true) => contacts[i].beyond += length,
+                (true, false) => contacts[j].beyond += length,
+                _ => {}
+            }
+        }
+    }
+    for (i, c) in contacts.iter_mut().enumerate() {
+        if outside[i] {
+            *c = Contacts::default()
+        } else {
+            c.neighbors.sort_by_key(|&(j, _)| j)
+        }
+    }
+
+    // (the mean perimeter of nodes, rather than the largest one, is scaled, so that weights do
+    // not hinge on a single node)
+    let total = |c: &Contacts| c.beyond + c.neighbors.iter().map(|&(_, l)| l).sum::<f64>();
+    let inside = contacts.iter().zip(outside).filter(|(_, &outside)| !outside);
+    let (sum_total, n_inside) = inside.fold((0., 0), |(sum, n), (c, _)| (sum + total(c), n + 1));
+    if sum_total > 0. {
+        let scale = Lattice::N_NEIGHBORS as f64 * n_inside as f64 / sum_total;
+        for c in contacts.iter_mut() {
+            c.beyond *= scale;
+            c.neighbors.iter_mut().for_each(|(_, weight)| *weight *= scale);
+        }
+    }
+    contacts
+}
+
+// Length of the edge shared by Voronoi cells of seeds i and j: the segment of the bisector of
+// the seeds that is closer to them than to seeds of other given nodes (adjacent to either), and,
+// unless periodic, that lies within the lattice.
+fn voronoi_edge_length<'a>(
+    seeds: &[[f64; 2]],
+    i: usize,
+    j: usize,
+    others: impl Iterator<Item = &'a usize>,
+    width: usize,
+    height: usize,
+    periodic: bool,
+) -> f64 {
+    let relative = |k: usize| displacement(seeds[i], seeds[k], width, height, periodic);
+    let dot = |a: [f64; 2], b: [f64; 2]| a[0] * b[0] + a[1] * b[1];
+    // points of the bisector, relative to seed i: m + s t
+    let q = relative(j);
+    let m = [q[0] / 2., q[1] / 2.];
+    let t = {
+        let norm = dot(q, q).sqrt();
+        [-q[1] / norm, q[0] / norm]
+    };
+    // restricts s to satisfy (m + s t) · n <= c
+    let (mut s_min, mut s_max) = (f64::NEG_INFINITY, f64::INFINITY);
+    let mut restrict = |n: [f64; 2], c: f64| {
+        let (tn, slack) = (dot(t, n), c - dot(m, n));
+        if tn > 0. {
+            s_max = s_max.min(slack / tn)
+        } else if tn < 0. {
+            s_min = s_min.max(slack / tn)
+        } else if slack < 0. {
+            s_max = f64::NEG_INFINITY
+        }
+    };
+    for &k in others {
+        let r = relative(k);
+        restrict(r, dot(r, r) / 2.);
+    }
+    if !periodic {
+        let [x, y] = seeds[i];
+        restrict([1., 0.], width as f64 - 0.5 - x);
+        restrict([-1., 0.], x + 0.5);
+        restrict([0., 1.], height as f64 - 0.5 - y);
+        restrict([0., -1.], y + 0.5);
+    }
+    (s_max - s_min).max(0.)
+}
+
+#[test]
+fn test_topology_contacts() {
+    use rand::SeedableRng;
+
+    let total = |c: &Contacts| c.beyond + c.neighbors.iter().map(|&(_, w)| w).sum::<f64>();
+    for topology in [Topology::Square4, Topology::Square8] {
+        let contacts = grid_contacts(topology, 5, 4, false, &[false; 20]);
+        let n_neighbors = if topology == Topology::Square4 { 4 } else { 8 };
+        assert_eq!(contacts[6].neighbors.len(), n_neighbors);
+        assert_eq!(contacts[0].neighbors.len(), n_neighbors / 2 - usize::from(n_neighbors == 8));
+        assert_eq!(total(&contacts[0]), total(&contacts[6]));
+    }
+
+    // Voronoi tissue covers the lattice area, and contacts are symmetric
+    let mut rng: Generator = SeedableRng::from_seed([123; 32]);
+    let (width, height) = (12, 10);
+    let seeds = voronoi_seeds(width, height, &mut rng);
+    for periodic in [true, false] {
+        let contacts = voronoi_contacts(&seeds, width, height, periodic, &[false; 120]);
+        let weight = |i: usize, j: usize| {
+            contacts[i].neighbors.iter().find(|&&(k, _)| k == j).map(|&(_, w)| w)
+        };
+        for (i, c) in contacts.iter().enumerate() {
+            assert!((2..=12).contains(&c.neighbors.len()));
+            for &(j, w) in c.neighbors.iter() {
+                assert!((weight(j, i).unwrap() - w).abs() < 1e-9);
+            }
+            let (x, y) = (i % width, i / width);
+            if periodic || ((2..width - 2).contains(&x) && (2..height - 2).contains(&y)) {
+                assert_eq!(c.beyond, 0.);
+            }
+        }
+        let mean_total = contacts.iter().map(total).sum::<f64>() / contacts.len() as f64;
+        assert!((mean_total - Lattice::N_NEIGHBORS as f64).abs() < 1e-9);
+        assert!(contacts.iter().any(|c| total(c) > Lattice::N_NEIGHBORS as f64));
+        assert_eq!(contacts.iter().any(|c| c.beyond > 0.), !periodic);
+        let owners = voronoi_raster(&seeds, width, height, periodic);
+        assert!((0..width * height).all(|i| owners.contains(&i)));
+    }
+}
@@ -0,0 +1,107 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Variability of rate constants among cells (extrinsic noise). Each lattice node gets its own
+// multiplicative factor of a rate constant, drawn when the lattice is created from a log-normal
+// or gamma distribution of mean 1 and the given coefficient of variation. Factors apply to rate
+// constants of events of the built-in wiring (see Simulation::set_cell_events_props), or, with
+// a model, to rates of reactions given by the parameter of the same name (see module model).
+// Factors are properties of cells rather than of the kinetics: they are kept in the lattice
+// (saved in checkpoints and state files) and stay unchanged when rates are set or loaded anew,
+// which is why their distributions are given in Geometry rather than in Rates.
+
+use crate::error::{Error, Result};
+use crate::randomness::Generator;
+
+use rand_distr::{Distribution, Gamma, LogNormal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// e.g. {"distribution": "lognormal", "cv": 0.3}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "distribution", rename_all = "lowercase")]
+pub enum Variability {
+    LogNormal { cv: f64 },
+    Gamma { cv: f64 },
+}
+
+impl Variability {
+    fn cv(self) -> f64 {
+        match self {
+            Variability::LogNormal { cv } | Variability::Gamma { cv } => cv,
+        }
+    }
+
+    fn sample(self, n: usize, rng: &mut Generator) -> Vec<f64> {
+        let cv = self.cv();
+        if cv == 0. {
+            return vec![1.; n];
+        }
+        match self {
+            Variability::LogNormal { .. } => {
+                let sigma2 = cv.powi(2).ln_1p();
+                let distribution = LogNormal::new(-sigma2 / 2., sigma2.sqrt()).unwrap();
+                distribution.sample_iter(rng).take(n).collect()
+            }
+            Variability::Gamma { .. } => {
+                let distribution = Gamma::new(1. / cv.powi(2), cv.powi(2)).unwrap();
+                distribution.sample_iter(rng).take(n).collect()
+            }
+        }
+    }
+}
+
+// per-node factors of a rate constant
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RateFactors {
+    pub name: String, // of the rate constant (in Rates)
+    pub factors: Vec<f64>,
+}
+
+// Draws factors for each of n lattice nodes (for rate constants in the order of their names).
+// (Names are checked only once the kinetics is known, see Kinetics::bind.)
+pub fn sample_rate_factors(
+    variability: &BTreeMap<String, Variability>,
+    n: usize,
+    rng: &mut Generator,
+) -> Result<Vec<RateFactors>> {
+    let mut rate_factors = Vec::with_capacity(variability.len());
+    for (name, &variability) in variability.iter() {
+        if !(variability.cv() >= 0. && variability.cv().is_finite()) {
+            let message = format!("coefficient of variation of {} {}", name, variability.cv());
+            return Err(Error::InvalidParameter(message));
+        }
+        let factors = variability.sample(n, rng);
+        rate_factors.push(RateFactors { name: name.clone(), factors })
+    }
+    Ok(rate_factors)
+}
+
+#[test]
+fn test_rate_factors() {
+    use rand::SeedableRng;
+    let mut rng: Generator = SeedableRng::from_seed([123; 32]);
+    let n = 100_000;
+    let variability = BTreeMap::from([
+        ("pstat_incr".to_string(), Variability::Gamma { cv: 0.5 }),
+        ("ifni_incr".to_string(), Variability::LogNormal { cv: 0.3 }),
+        ("die".to_string(), Variability::LogNormal { cv: 0. }),
+    ]);
+    let rate_factors = sample_rate_factors(&variability, n, &mut rng).unwrap();
+    let names = rate_factors.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["die", "ifni_incr", "pstat_incr"]);
+    assert!(rate_factors[0].factors.iter().all(|&f| f == 1.));
+    for (rate_factors, cv) in rate_factors[1..].iter().zip([0.3, 0.5]) {
+        let mean = rate_factors.factors.iter().sum::<f64>() / n as f64;
+        let variance = rate_factors.factors.iter().map(|f| (f - mean).powi(2)).sum::<f64>();
+        assert!((mean - 1.).abs() < 0.01);
+        assert!(((variance / n as f64).sqrt() - cv).abs() < 0.01);
+    }
+
+    for (name, cv) in [("die", -0.1), ("die", f64::NAN)] {
+        let variability = BTreeMap::from([(name.to_string(), Variability::Gamma { cv })]);
+        assert!(sample_rate_factors(&variability, n, &mut rng).is_err());
+    }
+}
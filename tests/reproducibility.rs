@@ -0,0 +1,54 @@
+// VIS-A-VIS, a simulator of Viral Infection Spread And Viral Infection Self-containment.
+//
+// Copyright (2022) Marek Kochanczyk & Frederic Grabowski (IPPT PAN, Warsaw).
+// Licensed under the 3-Clause BSD license (https://opensource.org/licenses/BSD-3-Clause).
+
+// Runs of the simulator given the same seed and protocol write identical output files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run(dir: &Path, protocol: &Path, seed: &str) -> PathBuf {
+    let out_dir = dir.join(format!("seed-{}", seed));
+    let status = Command::new(env!("CARGO_BIN_EXE_vis-a-vis"))
+        .args(["parameters/WT.json", protocol.to_str().unwrap()])
+        .args(["--width", "30", "--height", "30", "--seed", seed, "--force"])
+        .args(["--output-dir", out_dir.to_str().unwrap()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    out_dir
+}
+
+fn csv_files(out_dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut files = fs::read_dir(out_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "csv"))
+        .map(|path| (path.file_name().unwrap().to_string_lossy().into(), fs::read(path).unwrap()))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[test]
+fn test_same_seed_gives_identical_output_files() {
+    let dir = std::env::temp_dir().join(format!("visavis-reproducibility-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let protocol = dir.join("infection.protocol");
+    fs::write(&protocol, "+RSV 0.1 MOI\nrun 0m...3h [1h]\n").unwrap();
+
+    let first = csv_files(&run(&dir.join("first"), &protocol, "12345"));
+    let second_dir = run(&dir.join("second"), &protocol, "12345");
+    let names = first.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+    assert!(["summary.csv", "t_0000m.csv", "t_0180m.csv"].iter().all(|f| names.contains(f)));
+    assert_eq!(first, csv_files(&second_dir));
+    let manifest = fs::read_to_string(second_dir.join("manifest.json")).unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    assert_eq!(manifest["seed"], 12345);
+
+    let other = csv_files(&run(&dir.join("other"), &protocol, "54321"));
+    assert_ne!(first, other);
+    fs::remove_dir_all(&dir).unwrap();
+}